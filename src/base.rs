@@ -1,13 +1,18 @@
 use std::future::Future;
 use std::path::PathBuf;
+use std::time::Duration;
+use std::sync::mpsc::{Sender, Receiver, channel};
+use std::any::Any;
+use std::collections::HashMap;
 
 pub mod driver;
 use driver::logger::Logger;
 use driver::state::State;
 use driver::cache::Cache;
+use driver::store::Store;
 use driver::camera::Camera;
 use driver::clipboard::Clipboard;
-use driver::runtime::{Runtime, Tasks};
+use driver::runtime::{Runtime, Tasks, Task, TaskManager, TaskHandle, TaskError, TaskMetrics, SHUTDOWN_TIMEOUT};
 
 pub mod window;
 
@@ -15,8 +20,16 @@ pub mod renderer;
 pub use renderer::Renderer;
 pub use renderer::*;
 
+//Cache key of the persisted State
+//Under the cache's reserved prefix so it is never listed or evicted
+const STATE_KEY: &str = "rust_on_rails::cache::state";
+
 pub trait BaseAppTrait<R: Renderer> {
     const LOG_LEVEL: log::Level;
+    ///Time tasks are given to finish when the app closes
+    const SHUTDOWN_TIMEOUT: Duration = SHUTDOWN_TIMEOUT;
+    ///Registers Field migrations and encodings, runs before any stored data is read
+    fn register_fields() where Self: Sized {}
     fn background_tasks(ctx: &mut HeadlessContext) -> impl Future<Output = Tasks> where Self: Sized;
     fn new(
         ctx: Context<R>, h_ctx: &mut HeadlessContext, width: f32, height: f32
@@ -29,18 +42,54 @@ pub trait BaseAppTrait<R: Renderer> {
 #[derive(Debug, Clone)]
 pub struct HeadlessContext {
     pub cache: Cache,
+    ///Shared with the UI and every task of this process
+    pub store: Store,
+    pub(crate) tasks: TaskManager,
+    messages: Sender<Box<dyn Any + Send>>,
 }
 
 impl HeadlessContext {
-    async fn new(storage_path: PathBuf) -> Self {
-        HeadlessContext{
-            cache: Cache::new(storage_path).await,
-        }
+    fn new(cache: Cache, tasks: TaskManager, messages: Sender<Box<dyn Any + Send>>) -> Self {
+        HeadlessContext{cache, store: Store::default(), tasks, messages}
+    }
+
+    ///A context with an in memory cache running tasks on the current tokio runtime, useful for tests
+    pub fn memory() -> Self {
+        Self::new(Cache::memory(), TaskManager::new(tokio::runtime::Handle::current()), channel().0)
+    }
+
+    ///Sends a message to the UI, dropped when there is no UI to receive it
+    pub(crate) fn send(&self, message: Box<dyn Any + Send>) {
+        let _ = self.messages.send(message);
+    }
+
+    ///Spawns a task at runtime, it runs on its interval untill cancelled
+    pub fn spawn_task(&self, task: impl Task + 'static) -> TaskHandle {
+        self.tasks.spawn(Box::new(task), self.clone())
+    }
+
+    ///Spawns a one shot future on the runtime, dropped when the app shuts down
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) -> TaskHandle {
+        self.tasks.spawn_future(future)
+    }
+
+    ///Registers the hook called with the task name whenever a task fails after its retries
+    pub fn on_task_failure(&self, hook: impl Fn(&str, &TaskError) + Send + Sync + 'static) {
+        self.tasks.failures.set(hook)
+    }
+
+    ///Metrics of every running task, including the background tasks run in this process
+    pub fn task_metrics(&self) -> Vec<TaskMetrics> {self.tasks.metrics.get()}
+
+    pub fn log_task_metrics(&self, level: log::Level) {
+        self.task_metrics().iter().for_each(|metrics| log::log!(level, "{}", metrics));
     }
 }
 
 pub struct Context<R: Renderer> {
     state: State,
+    h_ctx: HeadlessContext,
+    messages: Receiver<Box<dyn Any + Send>>,
     r_ctx: R::Context
 }
 
@@ -49,11 +98,40 @@ impl<R: Renderer> AsMut<R::Context> for Context<R> {
 }
 
 impl<R: Renderer> Context<R> {
-    fn new(r_ctx: R::Context) -> Self {
-        Context{state: State::default(), r_ctx}
+    fn new(r_ctx: R::Context, h_ctx: HeadlessContext, messages: Receiver<Box<dyn Any + Send>>) -> Self {
+        Context{state: State::default(), h_ctx, messages, r_ctx}
     }
 
+    ///Messages sent by tasks since the last call
+    pub(crate) fn messages(&mut self) -> Vec<Box<dyn Any + Send>> {self.messages.try_iter().collect()}
+
     pub fn state(&mut self) -> &mut State {&mut self.state}
+    pub fn store(&self) -> &Store {&self.h_ctx.store}
+
+    ///Restores the persisted State from the Cache, done on start but an encrypted cache has to be unlocked first
+    pub async fn restore_state(&mut self) {
+        match self.h_ctx.cache.get_key::<HashMap<String, Vec<u8>>>(STATE_KEY).await {
+            Ok(Some(snapshot)) => self.state.merge(snapshot),
+            Ok(None) => {},
+            Err(e) => log::warn!("Failed to restore the state: {}", e)
+        }
+    }
+
+    ///Writes the persisted State to the Cache
+    pub async fn save_state(&self) {
+        let snapshot = self.state.persisted();
+        let result = if snapshot.is_empty() {
+            self.h_ctx.cache.delete(STATE_KEY).await.map(|_| ())
+        } else {
+            self.h_ctx.cache.set_key(STATE_KEY, &snapshot).await
+        };
+        if let Err(e) = result {log::error!("Failed to save the state: {}", e);}
+    }
+
+    pub fn spawn_task(&mut self, task: impl Task + 'static) -> TaskHandle {self.h_ctx.spawn_task(task)}
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + Send + 'static) -> TaskHandle {self.h_ctx.spawn(future)}
+    pub fn task_metrics(&mut self) -> Vec<TaskMetrics> {self.h_ctx.task_metrics()}
+    pub fn log_task_metrics(&mut self, level: log::Level) {self.h_ctx.log_task_metrics(level)}
 
     pub fn open_camera() -> Camera { Camera::new() }
     pub fn get_clipboard(&mut self) -> String { Clipboard::get() }
@@ -63,11 +141,13 @@ impl<R: Renderer> Context<R> {
 pub struct BackgroundApp;
 impl BackgroundApp {
     pub fn new_start<R: Renderer, A: BaseAppTrait<R>>(storage_path: PathBuf) {
+        A::register_fields();
         #[cfg(not(target_arch="wasm32"))]
-        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
         #[cfg(not(target_arch="wasm32"))]
         runtime.block_on(async {
-            let mut ctx = HeadlessContext::new(storage_path).await;
+            let tasks = TaskManager::new(tokio::runtime::Handle::current());
+            let mut ctx = HeadlessContext::new(Cache::open(storage_path).await, tasks, channel().0);
             let tasks = A::background_tasks(&mut ctx).await;
             Runtime::new_background(ctx, tasks).await;
         });
@@ -90,27 +170,34 @@ impl<R: Renderer, A: BaseAppTrait<R>> RenderAppTrait<R> for BaseApp<R, A> {
         storage_path: PathBuf, ctx: R::Context, width: f32, height: f32
     ) -> Self {
         Logger::start(A::LOG_LEVEL);        
-        let mut headless_ctx = HeadlessContext::new(storage_path.clone()).await;
-        let ctx = Context::new(ctx);
+        A::register_fields();
+        let runtime = Runtime::new();
+        let (sender, receiver) = channel();
+        let mut headless_ctx = HeadlessContext::new(Cache::open(storage_path.clone()).await, runtime.tasks(), sender);
+        let mut ctx = Context::new(ctx, headless_ctx.clone(), receiver);
+        ctx.restore_state().await;
         let background_tasks = if cfg!(any(target_os = "ios", target_os = "android")) {
             A::background_tasks(&mut headless_ctx).await
         } else {vec![]};
         let (app, tasks) = A::new(ctx, &mut headless_ctx, width, height).await;
-        let runtime = Runtime::new(headless_ctx, background_tasks, tasks).await;
+        runtime.start(&headless_ctx, background_tasks, tasks);
         BaseApp{
             _p: std::marker::PhantomData::<R>,
             runtime, app
         }
     }
     async fn on_event(&mut self, event: R::Event) {
-        if event.is_paused() {self.runtime.pause();}
+        let paused = event.is_paused();
+        if paused {self.runtime.pause();}
         if event.is_resumed() {self.runtime.resume();}
         self.app.on_event(event);
+        if paused {self.app.ctx().save_state().await;}
     }
 
     async fn close(mut self) -> R::Context {
         let ctx = self.app.close().await;
-        self.runtime.close();
+        ctx.save_state().await;
+        self.runtime.close(A::SHUTDOWN_TIMEOUT).await;
         ctx.r_ctx
     }
 
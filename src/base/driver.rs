@@ -4,3 +4,4 @@ pub mod camera;
 pub mod clipboard;
 pub mod state;
 pub mod cache;
+pub mod store;
@@ -1,51 +1,609 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::fmt::Debug;
+use std::time::{Duration, Instant};
 
 #[cfg(target_os = "android")]
 use winit_crate::platform::android::activity::AndroidApp;
 
+use tokio::sync::watch;
+
+use super::state::{Field, FieldError};
+use super::runtime::{Task, TaskResult, async_trait};
+use crate::base::HeadlessContext;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod sqlite;
 #[cfg(not(target_arch = "wasm32"))]
-use tokio::sync::Mutex;
+pub use sqlite::SqliteBackend;
+mod memory;
+pub use memory::MemoryBackend;
+mod encryption;
+pub use encryption::CacheKey;
+use encryption::{Cipher, Encryption};
+
+///Keys under this prefix are bookkeeping, they are never listed or evicted
+pub(crate) const RESERVED: &str = "rust_on_rails::cache::";
+const SALT: &str = "rust_on_rails::cache::salt";
+//Encrypted with the current key to recognize a wrong one
+const CHECK: &str = "rust_on_rails::cache::check";
+
+#[derive(Debug)]
+pub enum CacheError {
+    Io(std::io::Error),
+    #[cfg(not(target_arch = "wasm32"))]
+    Sql(rusqlite::Error),
+    ///The database file is damaged or not a database
+    Corrupt(String),
+    ///A stored value could not be decoded
+    Decode(String),
+    Field(FieldError),
+    ///The key or passphrase does not match the one the cache was encrypted with
+    WrongKey,
+    ///The cache is encrypted and has not been unlocked yet
+    Locked
+}
 
-use super::state::Field;
+impl CacheError {
+    pub fn is_corrupt(&self) -> bool {
+        match self {
+            CacheError::Corrupt(_) => true,
+            #[cfg(not(target_arch = "wasm32"))]
+            CacheError::Sql(e) => matches!(
+                e.sqlite_error_code(),
+                Some(rusqlite::ErrorCode::DatabaseCorrupt | rusqlite::ErrorCode::NotADatabase)
+            ),
+            _ => false
+        }
+    }
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CacheError::Io(e) => write!(f, "Cache io error: {}", e),
+            #[cfg(not(target_arch = "wasm32"))]
+            CacheError::Sql(e) => write!(f, "Cache sql error: {}", e),
+            CacheError::Corrupt(e) => write!(f, "Cache is corrupt: {}", e),
+            CacheError::Decode(e) => write!(f, "Cache value could not be decoded: {}", e),
+            CacheError::Field(e) => write!(f, "Cache value could not be read: {}", e),
+            CacheError::WrongKey => write!(f, "Cache key is wrong"),
+            CacheError::Locked => write!(f, "Cache is encrypted and locked")
+        }
+    }
+}
+
+impl std::error::Error for CacheError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CacheError::Io(e) => Some(e),
+            #[cfg(not(target_arch = "wasm32"))]
+            CacheError::Sql(e) => Some(e),
+            CacheError::Field(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<std::io::Error> for CacheError {
+    fn from(e: std::io::Error) -> Self {CacheError::Io(e)}
+}
 
 #[cfg(not(target_arch = "wasm32"))]
+impl From<rusqlite::Error> for CacheError {
+    fn from(e: rusqlite::Error) -> Self {CacheError::Sql(e)}
+}
+
+impl From<FieldError> for CacheError {
+    fn from(e: FieldError) -> Self {CacheError::Field(e)}
+}
+
+impl From<hex::FromHexError> for CacheError {
+    fn from(e: hex::FromHexError) -> Self {CacheError::Decode(e.to_string())}
+}
+
+///Storage behind a Cache, values arrive encoded and times are milliseconds since the epoch
+pub trait CacheBackend: Send + Debug {
+    ///Reads an entry that has not expired, marking it as used now
+    fn get(&mut self, key: &str, now: i64) -> Result<Option<Vec<u8>>, CacheError>;
+    fn set(&mut self, key: &str, value: Vec<u8>, expires: Option<i64>, now: i64) -> Result<(), CacheError>;
+    ///Deletes the entry, returning whether it existed
+    fn delete(&mut self, key: &str) -> Result<bool, CacheError>;
+    ///Entries that have not expired with a key starting with the prefix, in key order
+    fn scan(&mut self, prefix: &str, now: i64) -> Result<Vec<(String, Vec<u8>)>, CacheError>;
+    fn keys(&mut self, prefix: &str, now: i64) -> Result<Vec<String>, CacheError> {
+        Ok(self.scan(prefix, now)?.into_iter().map(|(key, _)| key).collect())
+    }
+    ///Replaces the value of an entry, keeping its expiry and recency
+    fn update(&mut self, key: &str, value: Vec<u8>) -> Result<(), CacheError>;
+    ///Keys written by other processes since the last call
+    fn changes(&mut self) -> Result<Vec<String>, CacheError> {Ok(vec![])}
+    fn begin(&mut self) -> Result<(), CacheError>;
+    fn commit(&mut self) -> Result<(), CacheError>;
+    fn rollback(&mut self) -> Result<(), CacheError>;
+    ///Removes expired entries, then the least recently used entries outside of `RESERVED` until the keys
+    ///and values take at most `max_size` bytes, and compacts the storage. Returns the number of entries removed
+    fn maintain(&mut self, max_size: Option<u64>, now: i64) -> Result<usize, CacheError>;
+}
+
+///How often subscriptions look for writes by other processes
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Default)]
+struct Watchers {
+    keys: Mutex<HashMap<String, watch::Sender<()>>>,
+    polled: Mutex<Option<Instant>>
+}
+
+impl Watchers {
+    fn subscribe(&self, key: &str) -> watch::Receiver<()> {
+        lock(&self.keys).entry(key.to_string()).or_insert_with(|| watch::channel(()).0).subscribe()
+    }
+
+    fn notify<'a>(&self, keys: impl IntoIterator<Item = &'a str>) {
+        let mut watchers = lock(&self.keys);
+        for key in keys {
+            if let Some(sender) = watchers.get(key) {
+                //Dropped subscriptions stop the watch
+                if sender.send(()).is_err() {watchers.remove(key);}
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
-pub struct Cache(
-    Arc<Mutex<rusqlite::Connection>>
-);
+pub struct Cache {
+    backend: Arc<Mutex<Box<dyn CacheBackend>>>,
+    watchers: Arc<Watchers>,
+    encryption: Arc<Mutex<Encryption>>
+}
 
-#[cfg(not(target_arch = "wasm32"))]
 impl Cache {
-    pub(crate) async fn new(storage_path: PathBuf) -> Self {
-        std::fs::create_dir_all(&storage_path).unwrap();
-        let path = storage_path.join("cache.db");
-        let db = rusqlite::Connection::open(path).unwrap();
-        db.execute(
-            "CREATE TABLE if not exists kvs(key TEXT NOT NULL UNIQUE, value TEXT);", []
-        ).unwrap();
-        Cache(Arc::new(Mutex::new(db)))
-    }
-
-    pub async fn set<F: Field + 'static>(&self, item: &F) {
-        self.0.lock().await.execute(
-            "INSERT INTO kvs(key, value) VALUES (?1, ?2) ON CONFLICT(key) DO UPDATE SET value=excluded.value;",
-            [F::ident(), hex::encode(item.to_bytes())]
-        ).unwrap();
-    }
-    pub async fn get<F: Field + 'static>(&self) -> F {
-        let db = self.0.lock().await;
-        let mut stmt = db.prepare(&format!(
-            "SELECT value FROM kvs where key = \'{}\'",
-            F::ident()
-        )).unwrap();
-        let result = stmt.query_and_then([], |row| {
-            let item: String = row.get(0).unwrap();
-            Ok(hex::decode(item).unwrap())
-        }).unwrap().collect::<Result<Vec<Vec<u8>>, rusqlite::Error>>().unwrap();
-        result.first().map(|b| F::from_bytes(b)).unwrap_or_default()
-    }
-}
-
-//TODO: WASM Cache
+    pub fn from_backend(mut backend: impl CacheBackend + 'static) -> Self {
+        let encrypted = Self::encrypted(&mut backend);
+        Cache{
+            backend: Arc::new(Mutex::new(Box::new(backend))),
+            watchers: Arc::default(),
+            encryption: Arc::new(Mutex::new(if encrypted {Encryption::Locked} else {Encryption::Plain}))
+        }
+    }
+
+    ///A cache that only lives as long as the app
+    pub fn memory() -> Self {Self::from_backend(MemoryBackend::default())}
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn new(storage_path: PathBuf) -> Result<Self, CacheError> {
+        Ok(Self::from_backend(SqliteBackend::new(&storage_path)?))
+    }
+
+    ///Opens the cache, backing up and recreating a corrupt database.
+    ///Falls back to an in memory cache when the database still can't be opened
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn open(storage_path: PathBuf) -> Self {
+        let error = match Self::new(storage_path.clone()).await {
+            Ok(cache) => return cache,
+            Err(e) if e.is_corrupt() => match SqliteBackend::backup(&storage_path) {
+                Ok(backup) => {
+                    log::warn!("{}, moved it to {} and starting over", e, backup.display());
+                    match Self::new(storage_path).await {
+                        Ok(cache) => return cache,
+                        Err(e) => e
+                    }
+                },
+                Err(backup) => {
+                    log::error!("{}, failed to back it up: {}", e, backup);
+                    e
+                }
+            },
+            Err(e) => e
+        };
+        log::error!("{}, using an in memory cache", error);
+        Self::memory()
+    }
+
+    //TODO: WASM backend
+    #[cfg(target_arch = "wasm32")]
+    pub async fn open(_storage_path: PathBuf) -> Self {Self::memory()}
+
+    ///Stores the item under `F::ident()`, use `set_key` to keep entries across renames
+    pub async fn set<F: Field + 'static>(&self, item: &F) -> Result<(), CacheError> {
+        self.set_key(&F::ident(), item).await
+    }
+    pub async fn get<F: Field + 'static>(&self) -> Result<F, CacheError> {
+        Ok(self.get_key(&F::ident()).await?.unwrap_or_default())
+    }
+
+    ///Stores the item under `F::ident()`, reading back as absent once the ttl has passed
+    pub async fn set_with_ttl<F: Field + 'static>(&self, item: &F, ttl: Duration) -> Result<(), CacheError> {
+        self.set_key_with_ttl(&F::ident(), item, ttl).await
+    }
+
+    pub async fn set_key<F: Field>(&self, key: &str, item: &F) -> Result<(), CacheError> {
+        let mut backend = lock(&self.backend);
+        let value = seal(&self.write_cipher(&mut **backend)?, key, item.to_bytes());
+        backend.set(key, value, None, now())?;
+        drop(backend);
+        self.watchers.notify([key]);
+        Ok(())
+    }
+    pub async fn set_key_with_ttl<F: Field>(&self, key: &str, item: &F, ttl: Duration) -> Result<(), CacheError> {
+        let mut backend = lock(&self.backend);
+        let value = seal(&self.write_cipher(&mut **backend)?, key, item.to_bytes());
+        backend.set(key, value, Some(expires(ttl)), now())?;
+        drop(backend);
+        self.watchers.notify([key]);
+        Ok(())
+    }
+    pub async fn get_key<F: Field>(&self, key: &str) -> Result<Option<F>, CacheError> {
+        let cipher = self.cipher()?;
+        let value = lock(&self.backend).get(key, now())?;
+        Ok(value.map(|b| unseal(&cipher, key, b)).transpose()?.map(|b| F::from_bytes(&b)).transpose()?)
+    }
+
+    ///Deletes the entry, returning whether it existed
+    pub async fn delete(&self, key: &str) -> Result<bool, CacheError> {
+        let deleted = lock(&self.backend).delete(key)?;
+        if deleted {self.watchers.notify([key]);}
+        Ok(deleted)
+    }
+
+    ///Runs the reads and writes atomically, committing when the closure returns Ok and rolling back otherwise
+    pub async fn transaction<T>(
+        &self, f: impl FnOnce(&mut CacheTransaction) -> Result<T, CacheError>
+    ) -> Result<T, CacheError> {
+        let (result, written) = {
+            let mut backend = lock(&self.backend);
+            let mut uncommitted = Uncommitted::begin(&mut **backend)?;
+            let cipher = self.write_cipher(&mut *uncommitted)?;
+            let mut transaction = CacheTransaction{backend: uncommitted, cipher, written: vec![]};
+            let result = f(&mut transaction)?;
+            let CacheTransaction{backend: uncommitted, written, ..} = transaction;
+            uncommitted.commit()?;
+            (result, written)
+        };
+        self.watchers.notify(written.iter().map(String::as_str));
+        Ok(result)
+    }
+
+    ///Reads the entries under the lock once, in the order of the keys
+    pub async fn get_many<F: Field>(&self, keys: &[&str]) -> Result<Vec<Option<F>>, CacheError> {
+        self.transaction(|t| keys.iter().map(|key| t.get_key(key)).collect()).await
+    }
+
+    ///Writes the entries atomically under the lock once
+    pub async fn set_many<F: Field>(&self, entries: &[(&str, F)]) -> Result<(), CacheError> {
+        self.transaction(|t| entries.iter().try_for_each(|(key, item)| t.set_key(key, item))).await
+    }
+
+    ///Every key starting with the prefix, in order
+    pub async fn keys(&self, prefix: &str) -> Result<Vec<String>, CacheError> {
+        let keys = lock(&self.backend).keys(prefix, now())?;
+        Ok(keys.into_iter().filter(|key| !key.starts_with(RESERVED)).collect())
+    }
+
+    ///Every entry with a key starting with the prefix, in key order
+    pub async fn scan<F: Field>(&self, prefix: &str) -> Result<Vec<(String, F)>, CacheError> {
+        let cipher = self.cipher()?;
+        let entries = lock(&self.backend).scan(prefix, now())?;
+        entries.into_iter().filter(|(key, _)| !key.starts_with(RESERVED)).map(|(key, b)| {
+            let item = F::from_bytes(&unseal(&cipher, &key, b)?)?;
+            Ok((key, item))
+        }).collect()
+    }
+
+    ///Removes expired entries, then evicts the least recently used entries until the
+    ///keys and values take at most `max_size` bytes and compacts the storage.
+    ///Returns the number of entries removed
+    pub async fn maintain(&self, max_size: Option<u64>) -> Result<usize, CacheError> {
+        lock(&self.backend).maintain(max_size, now())
+    }
+
+    ///Watches for writes and deletes of `F::ident()`
+    pub fn subscribe<F: Field + 'static>(&self) -> CacheSubscription {self.subscribe_key(&F::ident())}
+
+    ///Watches for writes and deletes of the key, including writes by other processes sharing the storage
+    pub fn subscribe_key(&self, key: &str) -> CacheSubscription {
+        CacheSubscription{cache: self.clone(), receiver: self.watchers.subscribe(key)}
+    }
+
+    ///Decrypts the cache with the key, encrypting the entries when it is not encrypted yet
+    pub async fn unlock(&self, key: CacheKey) -> Result<(), CacheError> {
+        let cipher = Cipher::new(&key);
+        let mut backend = lock(&self.backend);
+        match backend.get(CHECK, now())? {
+            Some(check) => {cipher.decrypt(CHECK, &check).map_err(|_| CacheError::WrongKey)?;},
+            None => Self::rekey(&mut **backend, None, &cipher, None)?
+        }
+        *lock(&self.encryption) = Encryption::Unlocked(cipher);
+        Ok(())
+    }
+
+    ///Unlocks with a key derived from the passphrase and a salt stored in the cache
+    pub async fn unlock_with_passphrase(&self, passphrase: &str) -> Result<(), CacheError> {
+        let salt = {
+            let mut backend = lock(&self.backend);
+            match backend.get(SALT, now())? {
+                Some(salt) => salt,
+                None => {
+                    let salt = CacheKey::salt().to_vec();
+                    backend.set(SALT, salt.clone(), None, now())?;
+                    salt
+                }
+            }
+        };
+        self.unlock(CacheKey::from_passphrase(passphrase, &salt)).await
+    }
+
+    ///Re-encrypts every entry with the new key, the cache has to be unlocked or not encrypted
+    pub async fn rotate_key(&self, key: CacheKey) -> Result<(), CacheError> {
+        self.rotate(Cipher::new(&key), None).await
+    }
+
+    ///Re-encrypts every entry with a key derived from the new passphrase and a new salt
+    pub async fn rotate_passphrase(&self, passphrase: &str) -> Result<(), CacheError> {
+        let salt = CacheKey::salt();
+        self.rotate(Cipher::new(&CacheKey::from_passphrase(passphrase, &salt)), Some(&salt)).await
+    }
+
+    async fn rotate(&self, cipher: Cipher, salt: Option<&[u8]>) -> Result<(), CacheError> {
+        let mut backend = lock(&self.backend);
+        let old = self.write_cipher(&mut **backend)?;
+        Self::rekey(&mut **backend, old.as_ref(), &cipher, salt)?;
+        *lock(&self.encryption) = Encryption::Unlocked(cipher);
+        Ok(())
+    }
+
+    //Atomically moves every entry, expired ones included, from the old cipher to the new one
+    fn rekey(backend: &mut dyn CacheBackend, old: Option<&Cipher>, new: &Cipher, salt: Option<&[u8]>) -> Result<(), CacheError> {
+        let mut backend = Uncommitted::begin(backend)?;
+        for (key, value) in backend.scan("", i64::MIN)? {
+            if key == SALT || key == CHECK {continue;}
+            let value = match old {
+                Some(old) => old.decrypt(&key, &value)?,
+                None => value
+            };
+            backend.update(&key, new.encrypt(&key, &value))?;
+        }
+        if let Some(salt) = salt {backend.set(SALT, salt.to_vec(), None, now())?;}
+        backend.set(CHECK, new.encrypt(CHECK, CHECK.as_bytes()), None, now())?;
+        backend.commit()
+    }
+
+    fn encrypted(backend: &mut dyn CacheBackend) -> bool {backend.keys(CHECK, now()).is_ok_and(|keys| !keys.is_empty())}
+
+    //Another process sharing the storage may have encrypted it or rotated its key since it was unlocked,
+    //writing with a stale cipher would leave entries no other process can read
+    fn write_cipher(&self, backend: &mut dyn CacheBackend) -> Result<Option<Cipher>, CacheError> {
+        let check = backend.scan(CHECK, now())?.into_iter().find(|(key, _)| key == CHECK).map(|(_, check)| check);
+        {
+            let mut encryption = lock(&self.encryption);
+            let stale = match (&*encryption, check) {
+                (Encryption::Plain, Some(_)) => true,
+                (Encryption::Unlocked(cipher), Some(check)) => cipher.decrypt(CHECK, &check).is_err(),
+                _ => false
+            };
+            if stale {*encryption = Encryption::Locked;}
+        }
+        self.cipher()
+    }
+
+    fn cipher(&self) -> Result<Option<Cipher>, CacheError> {
+        match &*lock(&self.encryption) {
+            Encryption::Plain => Ok(None),
+            Encryption::Locked => Err(CacheError::Locked),
+            Encryption::Unlocked(cipher) => Ok(Some(cipher.clone()))
+        }
+    }
+
+    //Reports writes by other processes, at most once per poll interval
+    fn poll(&self) {
+        {
+            let mut polled = lock(&self.watchers.polled);
+            if polled.is_some_and(|polled| polled.elapsed() < POLL_INTERVAL) {return;}
+            *polled = Some(Instant::now());
+        }
+        let changes = lock(&self.backend).changes();
+        match changes {
+            Ok(keys) => self.watchers.notify(keys.iter().map(String::as_str)),
+            Err(e) => log::warn!("Failed to check the cache for changes: {}", e)
+        }
+    }
+}
+
+///Notifies about changes to a cache entry, see `Cache::subscribe`
+#[derive(Debug)]
+pub struct CacheSubscription {
+    cache: Cache,
+    receiver: watch::Receiver<()>
+}
+
+impl CacheSubscription {
+    ///Whether the entry changed since the last call, cheap enough to check every frame
+    pub fn changed(&mut self) -> bool {
+        self.cache.poll();
+        let changed = self.receiver.has_changed().unwrap_or_default();
+        self.receiver.borrow_and_update();
+        changed
+    }
+
+    ///Waits for the next change
+    pub async fn wait(&mut self) {
+        loop {
+            self.cache.poll();
+            tokio::select! {
+                _ = self.receiver.changed() => return,
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+}
+
+///Typed reads and writes inside `Cache::transaction`
+pub struct CacheTransaction<'a> {
+    backend: Uncommitted<'a>,
+    cipher: Option<Cipher>,
+    written: Vec<String>
+}
+
+impl CacheTransaction<'_> {
+    pub fn set<F: Field + 'static>(&mut self, item: &F) -> Result<(), CacheError> {self.set_key(&F::ident(), item)}
+    pub fn get<F: Field + 'static>(&mut self) -> Result<F, CacheError> {Ok(self.get_key(&F::ident())?.unwrap_or_default())}
+
+    pub fn set_key<F: Field>(&mut self, key: &str, item: &F) -> Result<(), CacheError> {
+        self.backend.set(key, seal(&self.cipher, key, item.to_bytes()), None, now())?;
+        self.written.push(key.to_string());
+        Ok(())
+    }
+    pub fn set_key_with_ttl<F: Field>(&mut self, key: &str, item: &F, ttl: Duration) -> Result<(), CacheError> {
+        self.backend.set(key, seal(&self.cipher, key, item.to_bytes()), Some(expires(ttl)), now())?;
+        self.written.push(key.to_string());
+        Ok(())
+    }
+    pub fn get_key<F: Field>(&mut self, key: &str) -> Result<Option<F>, CacheError> {
+        let value = self.backend.get(key, now())?;
+        Ok(value.map(|b| unseal(&self.cipher, key, b)).transpose()?.map(|b| F::from_bytes(&b)).transpose()?)
+    }
+    pub fn delete(&mut self, key: &str) -> Result<bool, CacheError> {
+        let deleted = self.backend.delete(key)?;
+        if deleted {self.written.push(key.to_string());}
+        Ok(deleted)
+    }
+}
+
+//An open backend transaction, rolled back when dropped without committing, including while unwinding
+struct Uncommitted<'a>(&'a mut dyn CacheBackend, bool);
+
+impl<'a> Uncommitted<'a> {
+    fn begin(backend: &'a mut dyn CacheBackend) -> Result<Self, CacheError> {
+        backend.begin()?;
+        Ok(Uncommitted(backend, true))
+    }
+
+    ///Rolls back when the commit fails so the next transaction can begin
+    fn commit(mut self) -> Result<(), CacheError> {
+        self.1 = false;
+        let result = self.0.commit();
+        if result.is_err() {let _ = self.0.rollback();}
+        result
+    }
+}
+
+impl<'a> std::ops::Deref for Uncommitted<'a> {
+    type Target = dyn CacheBackend + 'a;
+    fn deref(&self) -> &Self::Target {self.0}
+}
+
+impl std::ops::DerefMut for Uncommitted<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {self.0}
+}
+
+impl Drop for Uncommitted<'_> {
+    fn drop(&mut self) {
+        if self.1 {
+            if let Err(e) = self.0.rollback() {log::error!("Failed to roll back a cache transaction: {}", e);}
+        }
+    }
+}
+
+//A panic while holding a lock leaves the backend consistent since transactions roll back on unwind
+pub(crate) fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {mutex.lock().unwrap_or_else(PoisonError::into_inner)}
+
+fn seal(cipher: &Option<Cipher>, key: &str, value: Vec<u8>) -> Vec<u8> {
+    match cipher {
+        Some(cipher) => cipher.encrypt(key, &value),
+        None => value
+    }
+}
+
+fn unseal(cipher: &Option<Cipher>, key: &str, value: Vec<u8>) -> Result<Vec<u8>, CacheError> {
+    match cipher {
+        Some(cipher) => cipher.decrypt(key, &value),
+        None => Ok(value)
+    }
+}
+
+//Durations too long to represent never expire
+fn expires(ttl: Duration) -> i64 {i64::try_from(ttl.as_millis()).map_or(i64::MAX, |ms| now().saturating_add(ms))}
+
+//Milliseconds since the epoch, used for expiry and recency
+fn now() -> i64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+///Built-in task running `Cache::maintain` on an interval
+#[derive(Debug, Clone)]
+pub struct CacheMaintenance {
+    pub interval: Duration,
+    ///Most bytes of keys and values to keep, None only removes expired entries
+    pub max_size: Option<u64>
+}
+
+impl CacheMaintenance {
+    pub fn new(max_size: Option<u64>) -> Self {CacheMaintenance{max_size, ..Default::default()}}
+}
+
+impl Default for CacheMaintenance {
+    fn default() -> Self {CacheMaintenance{interval: Duration::from_secs(60 * 60), max_size: None}}
+}
+
+#[async_trait]
+impl Task for CacheMaintenance {
+    fn interval(&self) -> Option<Duration> {Some(self.interval)}
+    fn id(&self) -> Option<String> {Some("rust_on_rails::CacheMaintenance".to_string())}
+
+    async fn run(&mut self, ctx: &mut HeadlessContext) -> TaskResult {
+        let removed = ctx.cache.maintain(self.max_size).await?;
+        log::info!("Cache maintenance removed {} entries", removed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expiry() {
+        assert_eq!(expires(Duration::MAX), i64::MAX);
+        assert_eq!(expires(Duration::from_millis(i64::MAX as u64)), i64::MAX);
+        assert!(expires(Duration::from_secs(1)) > now());
+    }
+
+    #[tokio::test]
+    async fn encryption() {
+        let cache = Cache::memory();
+        let reserved = format!("{}state", RESERVED);
+        cache.set_key("a", &1u8).await.unwrap();
+        cache.set_key(&reserved, &3u8).await.unwrap();
+        cache.unlock(CacheKey::new([1; 32])).await.unwrap();
+        assert_eq!(cache.get_key::<u8>("a").await.unwrap(), Some(1));
+        cache.rotate_passphrase("secret").await.unwrap();
+        cache.set_key("b", &2u8).await.unwrap();
+        assert!(matches!(cache.unlock(CacheKey::new([1; 32])).await, Err(CacheError::WrongKey)));
+        assert!(matches!(cache.unlock_with_passphrase("wrong").await, Err(CacheError::WrongKey)));
+        cache.unlock_with_passphrase("secret").await.unwrap();
+        assert_eq!(cache.get_many::<u8>(&["a", "b", &reserved]).await.unwrap(), vec![Some(1), Some(2), Some(3)]);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[tokio::test]
+    async fn shared_encryption() {
+        let path = std::env::temp_dir().join(format!("rust_on_rails_shared_encryption_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let (ui, background) = (Cache::new(path.clone()).await.unwrap(), Cache::new(path.clone()).await.unwrap());
+        ui.set_key("a", &1u8).await.unwrap();
+        ui.unlock(CacheKey::new([1; 32])).await.unwrap();
+        assert!(matches!(background.set_key("b", &2u8).await, Err(CacheError::Locked)));
+        assert!(matches!(background.get_key::<u8>("a").await, Err(CacheError::Locked)));
+        background.unlock(CacheKey::new([1; 32])).await.unwrap();
+        background.set_key("b", &2u8).await.unwrap();
+        ui.rotate_key(CacheKey::new([2; 32])).await.unwrap();
+        assert!(matches!(background.set_key("c", &3u8).await, Err(CacheError::Locked)));
+        assert!(matches!(background.transaction(|t| t.set_key("c", &3u8)).await, Err(CacheError::Locked)));
+        background.unlock(CacheKey::new([2; 32])).await.unwrap();
+        background.set_key("c", &3u8).await.unwrap();
+        assert_eq!(ui.get_many::<u8>(&["a", "b", "c"]).await.unwrap(), vec![Some(1), Some(2), Some(3)]);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
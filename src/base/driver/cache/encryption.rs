@@ -0,0 +1,69 @@
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, Key};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::aead::rand_core::RngCore;
+
+use super::CacheError;
+
+//Rounds of PBKDF2-HMAC-SHA256 when deriving a key from a passphrase
+const PBKDF2_ROUNDS: u32 = 210_000;
+const NONCE_SIZE: usize = 24;
+pub(super) const SALT_SIZE: usize = 16;
+
+///Key encrypting the values of a Cache
+#[derive(Clone)]
+pub struct CacheKey([u8; 32]);
+
+impl CacheKey {
+    pub fn new(key: [u8; 32]) -> Self {CacheKey(key)}
+
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> Self {
+        let mut key = [0; 32];
+        pbkdf2::pbkdf2_hmac::<sha2::Sha256>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+        CacheKey(key)
+    }
+
+    pub(super) fn salt() -> [u8; SALT_SIZE] {
+        let mut salt = [0; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        salt
+    }
+}
+
+impl std::fmt::Debug for CacheKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {write!(f, "CacheKey(..)")}
+}
+
+///Encrypts each value with a random nonce, bound to its key so values can't be swapped between rows
+#[derive(Clone)]
+pub(super) struct Cipher(XChaCha20Poly1305);
+
+impl Cipher {
+    pub fn new(key: &CacheKey) -> Self {Cipher(XChaCha20Poly1305::new(Key::from_slice(&key.0)))}
+
+    pub fn encrypt(&self, key: &str, value: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(self.0.encrypt(&nonce, Payload{msg: value, aad: key.as_bytes()}).unwrap());
+        sealed
+    }
+
+    pub fn decrypt(&self, key: &str, value: &[u8]) -> Result<Vec<u8>, CacheError> {
+        if value.len() < NONCE_SIZE {return Err(CacheError::Decode("Encrypted value is too short".to_string()));}
+        let (nonce, value) = value.split_at(NONCE_SIZE);
+        self.0.decrypt(XNonce::from_slice(nonce), Payload{msg: value, aad: key.as_bytes()})
+            .map_err(|_| CacheError::Decode(format!("Value of {} could not be decrypted", key)))
+    }
+}
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {write!(f, "Cipher(..)")}
+}
+
+#[derive(Debug, Default)]
+pub(super) enum Encryption {
+    #[default]
+    Plain,
+    ///Encrypted but no key was given yet
+    Locked,
+    Unlocked(Cipher)
+}
@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+
+use super::{CacheBackend, CacheError, RESERVED};
+
+#[derive(Debug, Clone)]
+struct Entry {
+    value: Vec<u8>,
+    expires: Option<i64>,
+    accessed: i64
+}
+
+impl Entry {
+    fn live(&self, now: i64) -> bool {self.expires.is_none_or(|expires| expires > now)}
+}
+
+///Cache backend keeping entries in memory, they only live as long as the app
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    entries: BTreeMap<String, Entry>,
+    //Entries as they were when the open transaction began
+    snapshot: Option<BTreeMap<String, Entry>>
+}
+
+impl CacheBackend for MemoryBackend {
+    fn get(&mut self, key: &str, now: i64) -> Result<Option<Vec<u8>>, CacheError> {
+        Ok(self.entries.get_mut(key).filter(|entry| entry.live(now)).map(|entry| {
+            entry.accessed = now;
+            entry.value.clone()
+        }))
+    }
+
+    fn set(&mut self, key: &str, value: Vec<u8>, expires: Option<i64>, now: i64) -> Result<(), CacheError> {
+        self.entries.insert(key.to_string(), Entry{value, expires, accessed: now});
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<bool, CacheError> {Ok(self.entries.remove(key).is_some())}
+
+    fn scan(&mut self, prefix: &str, now: i64) -> Result<Vec<(String, Vec<u8>)>, CacheError> {
+        Ok(self.entries.range(prefix.to_string()..).take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(_, entry)| entry.live(now))
+            .map(|(key, entry)| (key.clone(), entry.value.clone())).collect())
+    }
+
+    fn update(&mut self, key: &str, value: Vec<u8>) -> Result<(), CacheError> {
+        if let Some(entry) = self.entries.get_mut(key) {entry.value = value;}
+        Ok(())
+    }
+
+    fn begin(&mut self) -> Result<(), CacheError> {
+        self.snapshot = Some(self.entries.clone());
+        Ok(())
+    }
+    fn commit(&mut self) -> Result<(), CacheError> {
+        self.snapshot = None;
+        Ok(())
+    }
+    fn rollback(&mut self) -> Result<(), CacheError> {
+        if let Some(snapshot) = self.snapshot.take() {self.entries = snapshot;}
+        Ok(())
+    }
+
+    fn maintain(&mut self, max_size: Option<u64>, now: i64) -> Result<usize, CacheError> {
+        let count = self.entries.len();
+        self.entries.retain(|_, entry| entry.live(now));
+        if let Some(max_size) = max_size {
+            let mut recent = self.entries.iter().filter(|(key, _)| !key.starts_with(RESERVED)).map(|(key, entry)| (entry.accessed, key.clone(), key.len() + entry.value.len())).collect::<Vec<_>>();
+            recent.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+            let mut size = 0;
+            for (_, key, len) in recent {
+                size += len as u64;
+                if size > max_size {self.entries.remove(&key);}
+            }
+        }
+        Ok(count - self.entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maintain() {
+        let mut backend = MemoryBackend::default();
+        ["a", "b", "c"].iter().zip(1..).for_each(|(key, now)| backend.set(key, vec![0; 3], None, now).unwrap());
+        backend.set("t", vec![0; 3], Some(4), 1).unwrap();
+        backend.set(&format!("{}key", RESERVED), vec![0; 3], None, 0).unwrap();
+        backend.get("a", 4).unwrap();
+        //The expired entry and then the least recently used ones are removed, reserved entries are kept
+        assert_eq!(backend.maintain(Some(8), 5).unwrap(), 2);
+        let keys = backend.keys("", 5).unwrap();
+        assert_eq!(keys, vec!["a".to_string(), "c".to_string(), format!("{}key", RESERVED)]);
+    }
+
+    #[test]
+    fn rollback() {
+        let mut backend = MemoryBackend::default();
+        backend.set("a", vec![1], None, 0).unwrap();
+        backend.begin().unwrap();
+        backend.set("a", vec![2], None, 0).unwrap();
+        backend.delete("a").unwrap();
+        backend.rollback().unwrap();
+        assert_eq!(backend.get("a", 0).unwrap(), Some(vec![1]));
+    }
+}
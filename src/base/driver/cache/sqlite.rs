@@ -0,0 +1,198 @@
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use rusqlite::OptionalExtension;
+use rusqlite::types::Value;
+
+use super::{CacheBackend, CacheError, RESERVED};
+
+///How long a connection waits on a database locked by another process
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+///Reads only write the access time once it is this old, in ms, keeping reads free of write locks
+const ACCESS_RESOLUTION: i64 = 60_000;
+
+///Cache backend storing entries in a SQLite database
+#[derive(Debug)]
+pub struct SqliteBackend {
+    db: rusqlite::Connection,
+    //Changes by other connections, like the BackgroundApp, bump the data version
+    data_version: i64,
+    //Newest modification time already reported
+    seen: i64,
+    //Modification time of the keys this connection wrote that seen has not moved past yet
+    written: HashMap<String, i64>,
+    //Own writes from before the open transaction, a rollback forgets the ones made since
+    committed: Option<HashMap<String, i64>>
+}
+
+impl SqliteBackend {
+    ///Opens `cache.db` in the storage path, failing when it is corrupt
+    pub fn new(storage_path: &Path) -> Result<Self, CacheError> {
+        std::fs::create_dir_all(storage_path)?;
+        let db = rusqlite::Connection::open(storage_path.join("cache.db"))?;
+        db.busy_timeout(BUSY_TIMEOUT)?;
+        let check: String = db.query_row("PRAGMA quick_check;", [], |row| row.get(0))?;
+        if check != "ok" {return Err(CacheError::Corrupt(check));}
+        Self::from_connection(db)
+    }
+
+    pub fn from_connection(db: rusqlite::Connection) -> Result<Self, CacheError> {
+        db.execute(
+            "CREATE TABLE if not exists kvs(key TEXT NOT NULL UNIQUE, value BLOB, expires INTEGER, accessed INTEGER NOT NULL DEFAULT 0, modified INTEGER NOT NULL DEFAULT 0);", []
+        )?;
+        //Databases from before expiry, eviction and change tracking
+        for (column, definition) in [("expires", "INTEGER"), ("accessed", "INTEGER NOT NULL DEFAULT 0"), ("modified", "INTEGER NOT NULL DEFAULT 0")] {
+            let exists: u32 = db.query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('kvs') WHERE name = ?1;", [column], |row| row.get(0)
+            )?;
+            if exists == 0 {db.execute(&format!("ALTER TABLE kvs ADD COLUMN {} {};", column, definition), [])?;}
+        }
+        db.execute_batch("CREATE INDEX if not exists kvs_modified ON kvs(modified);")?;
+        //Values replaced when encrypting must not linger in free pages
+        db.execute_batch("PRAGMA secure_delete = ON;")?;
+        let data_version = db.query_row("PRAGMA data_version;", [], |row| row.get(0))?;
+        let seen = db.query_row("SELECT COALESCE(MAX(modified), 0) FROM kvs;", [], |row| row.get(0))?;
+        Ok(SqliteBackend{db, data_version, seen, written: HashMap::new(), committed: None})
+    }
+
+    ///Moves the database and its journals aside, returning the backup path
+    pub fn backup(storage_path: &Path) -> Result<PathBuf, CacheError> {
+        let secs = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+        let backup = storage_path.join(format!("cache.db.corrupt-{}", secs));
+        std::fs::rename(storage_path.join("cache.db"), &backup)?;
+        for journal in ["cache.db-wal", "cache.db-shm", "cache.db-journal"] {
+            let _ = std::fs::remove_file(storage_path.join(journal));
+        }
+        Ok(backup)
+    }
+
+    //Values used to be hex encoded JSON in TEXT, now they are BLOBs
+    fn bytes(value: Value) -> Result<Vec<u8>, CacheError> {
+        match value {
+            Value::Blob(bytes) => Ok(bytes),
+            Value::Text(hex) => Ok(hex::decode(hex)?),
+            _ => Err(CacheError::Decode("Value is not text or a blob".to_string()))
+        }
+    }
+}
+
+impl CacheBackend for SqliteBackend {
+    fn get(&mut self, key: &str, now: i64) -> Result<Option<Vec<u8>>, CacheError> {
+        let entry = self.db.prepare_cached(
+            "SELECT value, accessed FROM kvs WHERE key = ?1 AND (expires IS NULL OR expires > ?2);"
+        )?.query_row(rusqlite::params![key, now], |row| Ok((row.get::<_, Value>(0)?, row.get::<_, i64>(1)?))).optional()?;
+        let Some((value, accessed)) = entry else {return Ok(None);};
+        if now - accessed >= ACCESS_RESOLUTION {
+            self.db.prepare_cached("UPDATE kvs SET accessed = ?2 WHERE key = ?1;")?.execute(rusqlite::params![key, now])?;
+        }
+        Self::bytes(value).map(Some)
+    }
+
+    fn set(&mut self, key: &str, value: Vec<u8>, expires: Option<i64>, now: i64) -> Result<(), CacheError> {
+        //Modification times only increase, so a write by another process never looks like one of ours
+        let modified = self.db.prepare_cached(
+            "INSERT INTO kvs(key, value, expires, accessed, modified) VALUES (?1, ?2, ?3, ?4, MAX(?4, (SELECT COALESCE(MAX(modified), 0) + 1 FROM kvs))) ON CONFLICT(key) DO UPDATE SET value=excluded.value, expires=excluded.expires, accessed=excluded.accessed, modified=excluded.modified RETURNING modified;"
+        )?.query_row(rusqlite::params![key, value, expires, now], |row| row.get(0))?;
+        self.written.insert(key.to_string(), modified);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<bool, CacheError> {
+        Ok(self.db.prepare_cached("DELETE FROM kvs WHERE key = ?1;")?.execute([key])? > 0)
+    }
+
+    fn scan(&mut self, prefix: &str, now: i64) -> Result<Vec<(String, Vec<u8>)>, CacheError> {
+        let mut stmt = self.db.prepare_cached(
+            "SELECT key, value FROM kvs WHERE substr(key, 1, length(?1)) = ?1 AND (expires IS NULL OR expires > ?2) ORDER BY key;"
+        )?;
+        let entries = stmt.query_and_then(rusqlite::params![prefix, now], |row| {
+            Ok((row.get(0)?, Self::bytes(row.get(1)?)?))
+        })?.collect::<Result<Vec<_>, CacheError>>()?;
+        Ok(entries)
+    }
+
+    fn keys(&mut self, prefix: &str, now: i64) -> Result<Vec<String>, CacheError> {
+        let mut stmt = self.db.prepare_cached(
+            "SELECT key FROM kvs WHERE substr(key, 1, length(?1)) = ?1 AND (expires IS NULL OR expires > ?2) ORDER BY key;"
+        )?;
+        let keys = stmt.query_map(rusqlite::params![prefix, now], |row| row.get(0))?.collect::<Result<Vec<String>, _>>()?;
+        Ok(keys)
+    }
+
+    fn update(&mut self, key: &str, value: Vec<u8>) -> Result<(), CacheError> {
+        self.db.prepare_cached("UPDATE kvs SET value = ?2 WHERE key = ?1;")?.execute(rusqlite::params![key, value])?;
+        Ok(())
+    }
+
+    //Deletes by other processes are not seen, only their writes
+    fn changes(&mut self) -> Result<Vec<String>, CacheError> {
+        let data_version = self.db.query_row("PRAGMA data_version;", [], |row| row.get(0))?;
+        if data_version == self.data_version {return Ok(vec![]);}
+        self.data_version = data_version;
+        let mut stmt = self.db.prepare_cached("SELECT key, modified FROM kvs WHERE modified > ?1;")?;
+        let changed = stmt.query_map([self.seen], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(String, i64)>, _>>()?;
+        self.seen = changed.iter().map(|(_, modified)| *modified).max().unwrap_or(self.seen);
+        let changed = changed.into_iter().filter(|(key, modified)| self.written.get(key) != Some(modified)).map(|(key, _)| key).collect();
+        //Own writes are only forgotten once seen has moved past them, until then a scan could report them
+        let seen = self.seen;
+        self.written.retain(|_, modified| *modified > seen);
+        Ok(changed)
+    }
+
+    fn begin(&mut self) -> Result<(), CacheError> {
+        self.db.execute_batch("BEGIN;")?;
+        self.committed = Some(self.written.clone());
+        Ok(())
+    }
+    fn commit(&mut self) -> Result<(), CacheError> {
+        self.db.execute_batch("COMMIT;")?;
+        self.committed = None;
+        Ok(())
+    }
+    //Rolled back modification times can be handed out again, to another process
+    fn rollback(&mut self) -> Result<(), CacheError> {
+        self.db.execute_batch("ROLLBACK;")?;
+        if let Some(written) = self.committed.take() {self.written = written;}
+        Ok(())
+    }
+
+    fn maintain(&mut self, max_size: Option<u64>, now: i64) -> Result<usize, CacheError> {
+        let mut removed = self.db.prepare_cached("DELETE FROM kvs WHERE expires <= ?1;")?.execute([now])?;
+        if let Some(max_size) = max_size {
+            removed += self.db.prepare_cached(
+                "DELETE FROM kvs WHERE key IN (SELECT key FROM (
+                    SELECT key, SUM(length(key) + length(value)) OVER (ORDER BY accessed DESC, key) AS size FROM kvs
+                ) WHERE size > ?1) AND substr(key, 1, length(?2)) != ?2;"
+            )?.execute(rusqlite::params![max_size as i64, RESERVED])?;
+        }
+        self.db.execute_batch("VACUUM;")?;
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes() {
+        let path = std::env::temp_dir().join(format!("rust_on_rails_sqlite_changes_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let (mut own, mut foreign) = (SqliteBackend::new(&path).unwrap(), SqliteBackend::new(&path).unwrap());
+        //Both writes land in the same millisecond
+        own.set("own", vec![1], None, 100).unwrap();
+        foreign.set("foreign", vec![2], None, 100).unwrap();
+        assert_eq!(own.changes().unwrap(), vec!["foreign".to_string()]);
+        assert_eq!(foreign.changes().unwrap(), vec!["own".to_string()]);
+        assert!(own.changes().unwrap().is_empty());
+        //A rolled back write does not hide a foreign write given the same modification time
+        own.begin().unwrap();
+        own.set("key", vec![3], None, 200).unwrap();
+        own.rollback().unwrap();
+        foreign.set("key", vec![4], None, 200).unwrap();
+        assert_eq!(own.changes().unwrap(), vec!["key".to_string()]);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
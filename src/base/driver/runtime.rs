@@ -1,16 +1,46 @@
-use std::time::{Instant, Duration};
-use std::sync::mpsc::{TryRecvError, Sender, Receiver, channel};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::collections::hash_map::RandomState;
+use std::fmt::Debug;
+
+use tokio::time::{Instant, sleep_until, sleep};
+use tokio::sync::{watch, Semaphore};
+use tokio::runtime::Handle;
+use tokio::task::{JoinHandle, AbortHandle};
 
 use crate::base::HeadlessContext;
+use super::cache::lock;
 
-const THREAD_TICK: u64 = 16;
+mod cron;
+pub use cron::{Cron, InvalidCron};
 
 pub use async_trait::async_trait;
 
+pub type TaskError = Box<dyn std::error::Error + Send + Sync>;
+pub type TaskResult = Result<(), TaskError>;
+
 #[async_trait]
 pub trait Task: Send {
-    fn interval(&self) -> Option<Duration>;
-    async fn run(&mut self, ctx: &mut HeadlessContext);
+    fn interval(&self) -> Option<Duration> {None}
+    ///When the task runs, defaults to every interval
+    fn schedule(&self) -> Schedule {self.interval().map(Schedule::Interval).unwrap_or(Schedule::Never)}
+    ///Stable identifier the last run is persisted under so schedules carry over restarts,
+    ///without one the schedule starts over from when the task is spawned
+    fn id(&self) -> Option<String> {None}
+    ///What the task does while the app is paused (backgrounded, suspended or hidden)
+    fn on_pause(&self) -> PausePolicy {PausePolicy::Stop}
+    ///Tasks sharing a Limit never run more of them at once than its permits
+    fn limit(&self) -> Option<Limit> {None}
+    ///Policy for retrying a failed run, failures are reported once the retries run out
+    fn retry(&self) -> Retry {Retry::default()}
+    fn name(&self) -> String {std::any::type_name_of_val(self).to_string()}
+    async fn run(&mut self, ctx: &mut HeadlessContext) -> TaskResult;
+    ///Called once when the runtime shuts down, after any run in progress has finished
+    async fn on_shutdown(&mut self, _ctx: &mut HeadlessContext) {}
 }
 
 pub type Tasks = Vec<Box<dyn Task>>;
@@ -22,23 +52,367 @@ macro_rules! tasks {
     };
 }
 
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    ///Runs each time the interval elapses since the last run, including runs from previous launches
+    Interval(Duration),
+    ///Runs at every matching minute, a time missed while the app was closed runs on launch
+    Cron(Cron),
+    ///Runs once when the task starts
+    Startup,
+    Never
+}
+
+impl Schedule {
+    ///The next time to run given the last persisted run and the last run of this launch
+    fn due(&self, last: SystemTime, ran: Option<Instant>) -> Option<Instant> {
+        match self {
+            Schedule::Interval(interval) => Some(match ran {
+                Some(ran) => ran + *interval,
+                None => Instant::now() + interval.saturating_sub(last.elapsed().unwrap_or_default())
+            }),
+            Schedule::Cron(cron) => cron.next_after(last).map(|next|
+                Instant::now() + next.duration_since(SystemTime::now()).unwrap_or_default()
+            ),
+            Schedule::Startup => ran.is_none().then(Instant::now),
+            Schedule::Never => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PausePolicy {
+    ///Stops untill the app resumes
+    #[default]
+    Stop,
+    ///Keeps running on its schedule
+    Continue,
+    ///Keeps running but waits at least the duration between runs
+    Reduced(Duration)
+}
+
+///Last run of each task, stored in the cache as seconds since the epoch under the task id
+struct LastRuns;
+
+impl LastRuns {
+    const PREFIX: &'static str = "rust_on_rails::cache::task::last_run/";
+
+    ///Last run of the task, a task that never ran counts from now
+    async fn get(ctx: &HeadlessContext, id: &str) -> SystemTime {
+        //Schedules keep working from memory when the cache fails
+        match ctx.cache.get_key::<u64>(&Self::key(id)).await {
+            Ok(Some(secs)) => UNIX_EPOCH + Duration::from_secs(secs),
+            Ok(None) => {
+                let now = SystemTime::now();
+                Self::set(ctx, id, now).await;
+                now
+            },
+            Err(e) => {
+                log::warn!("Failed to load the last run of task {}: {}", id, e);
+                SystemTime::now()
+            }
+        }
+    }
+
+    async fn set(ctx: &HeadlessContext, id: &str, time: SystemTime) {
+        if let Err(e) = ctx.cache.set_key(&Self::key(id), &Self::secs(time)).await {
+            log::warn!("Failed to store the last run of task {}: {}", id, e);
+        }
+    }
+
+    fn key(id: &str) -> String {format!("{}{}", Self::PREFIX, id)}
+
+    fn secs(time: SystemTime) -> u64 {time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()}
+}
+
+///Concurrency limit shared between the tasks holding a clone of it
+#[derive(Debug, Clone)]
+pub struct Limit(Arc<Semaphore>);
+impl Limit {
+    pub fn new(permits: usize) -> Self {Limit(Arc::new(Semaphore::new(permits)))}
+}
+
+///Retries a failed run up to attempts times, waiting delay doubled on each attempt up to max_delay
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retry {
+    pub attempts: u32,
+    pub delay: Duration,
+    pub max_delay: Duration,
+    ///Randomizes each wait between half and all of its delay
+    pub jitter: bool
+}
+
+impl Retry {
+    pub fn exponential(attempts: u32, delay: Duration, max_delay: Duration) -> Self {
+        Retry{attempts, delay, max_delay, jitter: false}
+    }
+
+    pub fn with_jitter(mut self) -> Self {self.jitter = true; self}
+
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_delay);
+        if !self.jitter {return delay;}
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(attempt);
+        delay.mul_f64(0.5 + (hasher.finish() as f64 / u64::MAX as f64) / 2.0)
+    }
+}
+
+type OnFailure = Arc<dyn Fn(&str, &TaskError) + Send + Sync>;
+
+///Logs task failures and tells the app through its registered hook
+#[derive(Clone, Default)]
+pub struct Failures(Arc<Mutex<Option<OnFailure>>>);
+impl Failures {
+    pub fn set(&self, hook: impl Fn(&str, &TaskError) + Send + Sync + 'static) {
+        *lock(&self.0) = Some(Arc::new(hook));
+    }
+
+    pub fn report(&self, name: &str, error: &TaskError) {
+        log::error!("Task {} failed: {}", name, error);
+        //Called without the lock so the hook can replace itself or report again
+        let hook = lock(&self.0).clone();
+        if let Some(hook) = hook {hook(name, error);}
+    }
+}
+
+impl Debug for Failures {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {f.write_str("Failures")}
+}
+
+///What the runtime knows about a running task
+#[derive(Debug, Clone, Default)]
+pub struct TaskMetrics {
+    pub name: String,
+    pub id: Option<String>,
+    pub runs: u32,
+    ///Runs that still failed after their retries
+    pub failures: u32,
+    pub running: bool,
+    pub last_run: Option<SystemTime>,
+    pub next_run: Option<SystemTime>,
+    pub average_duration: Duration,
+    pub max_duration: Duration
+}
+
+impl std::fmt::Display for TaskMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let ago = |time: Option<SystemTime>| time.and_then(|t| t.elapsed().ok());
+        let until = |time: Option<SystemTime>| time.and_then(|t| t.duration_since(SystemTime::now()).ok());
+        write!(f,
+            "{} (runs: {}, failures: {}, running: {}, last run: {:?} ago, next run: in {:?}, average: {:?}, max: {:?})",
+            self.name, self.runs, self.failures, self.running, ago(self.last_run), until(self.next_run),
+            self.average_duration, self.max_duration
+        )
+    }
+}
+
+///Metrics of every task worker by worker id
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<Mutex<BTreeMap<u64, TaskMetrics>>>);
+impl Metrics {
+    pub fn get(&self) -> Vec<TaskMetrics> {self.0.lock().unwrap().values().cloned().collect()}
+
+    //Listed until the returned guard drops, so cancelled or panicked workers are removed too
+    fn register(&self, metrics: TaskMetrics) -> WorkerMetrics {
+        let worker = WORKER_ID.fetch_add(1, Ordering::Relaxed);
+        self.0.lock().unwrap().insert(worker, metrics);
+        WorkerMetrics(self.clone(), worker)
+    }
+}
+
+struct WorkerMetrics(Metrics, u64);
+impl WorkerMetrics {
+    fn update(&self, update: impl FnOnce(&mut TaskMetrics)) {
+        if let Some(metrics) = self.0.0.lock().unwrap().get_mut(&self.1) {update(metrics);}
+    }
+}
+
+impl Drop for WorkerMetrics {
+    fn drop(&mut self) {
+        if let Ok(mut metrics) = self.0.0.lock() {metrics.remove(&self.1);}
+    }
+}
+
+static WORKER_ID: AtomicU64 = AtomicU64::new(0);
+
+///Time the runtime waits for tasks to shut down before abandoning them
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Signal {Running, Paused, Shutdown}
+
+type Workers = Arc<Mutex<Vec<(String, JoinHandle<()>)>>>;
+
+///Handle to a spawned task or future
+#[derive(Debug, Clone)]
+pub struct TaskHandle(AbortHandle);
+impl TaskHandle {
+    pub fn cancel(&self) {self.0.abort();}
+    pub fn is_running(&self) -> bool {!self.0.is_finished()}
+}
+
+///Runs every task as its own worker on its own schedule,
+///a worker awaits its task before scheduling the next run so a task never overlaps with itself
+#[derive(Debug, Clone)]
 pub struct TaskManager {
-    ctx: HeadlessContext,
-    tasks: Vec<(Box<dyn Task>, Instant)> 
+    handle: Handle,
+    signal: Arc<watch::Sender<Signal>>,
+    workers: Workers,
+    pub(crate) failures: Failures,
+    pub(crate) metrics: Metrics
 }
 
 impl TaskManager {
-    pub fn new(ctx: HeadlessContext, tasks: Tasks) -> Self {
-        TaskManager{ctx, tasks: tasks.into_iter().map(|t| (t, Instant::now())).collect()}
+    pub fn new(handle: Handle) -> Self {
+        TaskManager{
+            handle,
+            signal: Arc::new(watch::channel(Signal::Running).0),
+            workers: Arc::default(),
+            failures: Failures::default(),
+            metrics: Metrics::default()
+        }
+    }
+
+    ///A manager with its own workers and signals that shares the failure hook and metrics
+    pub fn sibling(&self) -> Self {
+        TaskManager{
+            handle: self.handle.clone(),
+            signal: Arc::new(watch::channel(Signal::Running).0),
+            workers: Arc::default(),
+            failures: self.failures.clone(),
+            metrics: self.metrics.clone()
+        }
     }
 
-    pub async fn tick(&mut self) {
-        for (task, time) in self.tasks.iter_mut() {
-            if let Some(duration) = task.interval() {
-                if time.elapsed() > duration {
-                    *time = Instant::now();
-                    task.run(&mut self.ctx).await;
+    ///Spawns a worker running the task with a context that spawns onto this manager
+    pub fn spawn(&self, task: Box<dyn Task>, mut ctx: HeadlessContext) -> TaskHandle {
+        ctx.tasks = self.clone();
+        let name = task.name();
+        self.spawn_named(name, Self::worker(task, ctx, self.signal.subscribe()))
+    }
+
+    ///Spawns a one shot future, it is not paused and is dropped once shutdown is signaled
+    pub fn spawn_future(&self, future: impl Future<Output = ()> + Send + 'static) -> TaskHandle {
+        let name = std::any::type_name_of_val(&future).to_string();
+        let mut signal = self.signal.subscribe();
+        self.spawn_named(name, async move {
+            tokio::select! {
+                _ = future => {},
+                Ok(_) = signal.wait_for(|signal| *signal == Signal::Shutdown) => {}
+            }
+        })
+    }
+
+    fn spawn_named(&self, name: String, future: impl Future<Output = ()> + Send + 'static) -> TaskHandle {
+        let worker = self.handle.spawn(future);
+        let handle = TaskHandle(worker.abort_handle());
+        let mut workers = self.workers.lock().unwrap();
+        workers.retain(|(_, worker)| !worker.is_finished());
+        workers.push((name, worker));
+        handle
+    }
+
+    async fn worker(
+        mut task: Box<dyn Task>, mut ctx: HeadlessContext, mut signal: watch::Receiver<Signal>
+    ) {
+        let id = task.id();
+        let metrics = ctx.tasks.metrics.register(TaskMetrics{name: task.name(), id: id.clone(), ..Default::default()});
+        let mut last = match &id {
+            Some(id) => LastRuns::get(&ctx, id).await,
+            None => SystemTime::now()
+        };
+        let mut ran = None;
+        loop {
+            let due = task.schedule().due(last, ran);
+            let paused_due = match task.on_pause() {
+                PausePolicy::Stop => None,
+                PausePolicy::Continue => due,
+                PausePolicy::Reduced(interval) => due.map(|due| due.max(ran.unwrap_or_else(Instant::now) + interval))
+            };
+            metrics.update(|m| m.next_run = due.map(|due|
+                SystemTime::now() + due.saturating_duration_since(Instant::now())
+            ));
+            if !Self::wait(&mut signal, due, paused_due).await {break;}
+            let _permit = match task.limit() {
+                Some(limit) => Some(limit.0.acquire_owned().await.unwrap()),
+                None => None
+            };
+            let start = Instant::now();
+            ran = Some(start);
+            last = SystemTime::now();
+            metrics.update(|m| {m.running = true; m.last_run = Some(last);});
+            let retry = task.retry();
+            let mut attempt = 0;
+            let failed = loop {
+                let Err(error) = task.run(&mut ctx).await else {break false;};
+                if attempt >= retry.attempts {
+                    ctx.tasks.failures.report(&task.name(), &error);
+                    break true;
                 }
+                log::warn!("Task {} failed, retrying: {}", task.name(), error);
+                sleep(retry.backoff(attempt)).await;
+                attempt += 1;
+            };
+            let duration = start.elapsed();
+            metrics.update(|m| {
+                m.running = false;
+                m.runs += 1;
+                m.failures += failed as u32;
+                m.average_duration = (m.average_duration * (m.runs - 1) + duration) / m.runs;
+                m.max_duration = m.max_duration.max(duration);
+            });
+            if let Some(id) = &id {LastRuns::set(&ctx, id, last).await;}
+        }
+        task.on_shutdown(&mut ctx).await;
+    }
+
+    ///Sleeps untill due, or paused_due while paused, no due time waits for the next signal.
+    ///Returns false once shutdown is signaled
+    async fn wait(signal: &mut watch::Receiver<Signal>, due: Option<Instant>, paused_due: Option<Instant>) -> bool {
+        loop {
+            let current = *signal.borrow_and_update();
+            let due = match current {
+                Signal::Running => due,
+                Signal::Paused => paused_due,
+                Signal::Shutdown => return false
+            };
+            tokio::select! {
+                _ = async {match due {
+                    Some(due) => sleep_until(due).await,
+                    None => std::future::pending().await
+                }} => return true,
+                result = signal.changed() => if result.is_err() {return false;}
+            }
+        }
+    }
+
+    pub fn pause(&self) {self.set_signal(Signal::Paused);}
+    pub fn resume(&self) {self.set_signal(Signal::Running);}
+    pub fn shutdown(&self) {self.set_signal(Signal::Shutdown);}
+
+    fn set_signal(&self, new: Signal) {
+        self.signal.send_if_modified(|signal| {
+            let modified = *signal != Signal::Shutdown && *signal != new;
+            if modified {*signal = new;}
+            modified
+        });
+    }
+
+    ///Waits for every worker to return, including workers spawned while waiting
+    pub async fn join(&self) {
+        self.join_until(None).await;
+    }
+
+    ///Waits for every worker to return untill the deadline, returning the names of the unfinished workers
+    pub async fn join_until(&self, deadline: Option<Instant>) -> Vec<String> {
+        let mut unfinished = vec![];
+        loop {
+            let worker = self.workers.lock().unwrap().pop();
+            let Some((name, worker)) = worker else {return unfinished;};
+            match deadline {
+                Some(deadline) => if tokio::time::timeout_at(deadline, worker).await.is_err() {unfinished.push(name);},
+                None => {let _ = worker.await;}
             }
         }
     }
@@ -48,70 +422,133 @@ impl TaskManager {
 ///Runs Active Tasks (and Background Tasks on mobile/wasm)
 pub struct Runtime {
     runtime: Option<tokio::runtime::Runtime>,
-    active_thread: Sender<u8>,
+    active: TaskManager,
+    background: TaskManager
 }
 
 impl Runtime {
     pub async fn new_background(ctx: HeadlessContext, tasks: Tasks) {
-        Self::background_thread(TaskManager::new(ctx, tasks)).await
+        let task_manager = ctx.tasks.clone();
+        tasks.into_iter().for_each(|task| {task_manager.spawn(task, ctx.clone());});
+        task_manager.join().await
     }
 
-    pub async fn new(
-        ctx: HeadlessContext, background_tasks: Tasks, tasks: Tasks
-    ) -> Self {
+    pub fn new() -> Self {
         let threads = if cfg!(any(target_os = "ios", target_os = "android")) {2} else {1};
         let runtime = tokio::runtime::Builder::new_multi_thread().enable_time().enable_io().worker_threads(threads).build().unwrap();
+        let active = TaskManager::new(runtime.handle().clone());
+        let background = active.sibling();
+        Runtime{runtime: Some(runtime), active, background}
+    }
 
-        if !background_tasks.is_empty() {
-            let task_manager = TaskManager::new(ctx.clone(), background_tasks);
-            runtime.spawn(Self::background_thread(task_manager));
-        }
+    ///TaskManager for the Active Tasks, used by the app to spawn tasks at runtime
+    pub fn tasks(&self) -> TaskManager {self.active.clone()}
 
-        let (active_thread, receiver) = channel();
-        runtime.spawn(Self::thread(TaskManager::new(ctx, tasks), receiver));
+    pub fn start(&self, ctx: &HeadlessContext, background_tasks: Tasks, tasks: Tasks) {
+        background_tasks.into_iter().for_each(|task| {self.background.spawn(task, ctx.clone());});
+        tasks.into_iter().for_each(|task| {self.active.spawn(task, ctx.clone());});
+    }
 
-        Runtime{runtime: Some(runtime), active_thread}
+    pub fn pause(&mut self) {
+        self.active.pause();
     }
 
-    async fn thread(mut task_manager: TaskManager, receiver: Receiver<u8>) {
-        let mut paused = false;
-        loop {
-            if !paused {
-                match receiver.try_recv() {
-                    Ok(0) => {},
-                    Ok(1) => {paused = true}
-                    Err(TryRecvError::Empty) => {
-                        task_manager.tick().await;
-                    },
-                    _ => {return;}
-                }
-            } else {
-                match receiver.recv() {
-                    Ok(0) => {paused = false;},
-                    Ok(1) => {},
-                    _ => {return;}
-                }
-            }
-            std::thread::sleep(Duration::from_millis(THREAD_TICK));
-        }
+    pub fn resume(&mut self) {
+        self.active.resume();
     }
 
-    async fn background_thread(mut task_manager: TaskManager) {
-        loop {
-            task_manager.tick().await;
-            std::thread::sleep(Duration::from_millis(THREAD_TICK));
+    ///Signals every task to shut down and waits for them untill the timeout,
+    ///returning the names of the tasks that did not finish
+    pub async fn close(&mut self, timeout: Duration) -> Vec<String> {
+        self.active.shutdown();
+        self.background.shutdown();
+        let deadline = Some(Instant::now() + timeout);
+        let mut unfinished = self.active.join_until(deadline).await;
+        unfinished.extend(self.background.join_until(deadline).await);
+        unfinished.iter().for_each(|name| log::warn!("Task {} did not finish before the shutdown deadline", name));
+        self.runtime.take().unwrap().shutdown_background();
+        unfinished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Counter(Arc<AtomicU64>, Option<&'static str>);
+    #[async_trait]
+    impl Task for Counter {
+        fn interval(&self) -> Option<Duration> {Some(Duration::from_secs(10))}
+        fn id(&self) -> Option<String> {self.1.map(str::to_string)}
+        async fn run(&mut self, _ctx: &mut HeadlessContext) -> TaskResult {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
         }
     }
 
-    pub fn pause(&mut self) {
-        self.active_thread.send(1).unwrap();
+    #[tokio::test(start_paused = true)]
+    async fn schedule() {
+        let ctx = HeadlessContext::memory();
+        let runs = Arc::new(AtomicU64::new(0));
+        ctx.spawn_task(Counter(runs.clone(), None));
+        //A task that never ran waits a full interval
+        sleep(Duration::from_secs(35)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        ctx.tasks.pause();
+        sleep(Duration::from_secs(30)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        //The missed run happens as soon as the app resumes
+        ctx.tasks.resume();
+        sleep(Duration::from_millis(1)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 4);
+        assert_eq!(ctx.task_metrics()[0].runs, 4);
+        ctx.tasks.shutdown();
+        assert!(ctx.tasks.join_until(Some(Instant::now() + Duration::from_secs(1))).await.is_empty());
+        assert!(ctx.task_metrics().is_empty());
     }
 
-    pub fn resume(&mut self) {
-        self.active_thread.send(0).unwrap();
+    #[tokio::test(start_paused = true)]
+    async fn last_run() {
+        let ctx = HeadlessContext::memory();
+        ctx.spawn_task(Counter(Arc::default(), None));
+        ctx.spawn_task(Counter(Arc::default(), Some("counter")));
+        sleep(Duration::from_secs(15)).await;
+        //Only tasks with an id persist their last run, under the reserved prefix
+        assert!(ctx.cache.get_key::<u64>(&LastRuns::key("counter")).await.unwrap().is_some());
+        assert!(ctx.cache.get_key::<u64>(&LastRuns::key(std::any::type_name::<Counter>())).await.unwrap().is_none());
+        assert!(ctx.cache.keys("").await.unwrap().is_empty());
+        ctx.tasks.shutdown();
+        assert!(ctx.tasks.join_until(Some(Instant::now() + Duration::from_secs(1))).await.is_empty());
     }
 
-    pub fn close(&mut self) {
-        self.runtime.take().unwrap().shutdown_background();
+    #[test]
+    fn failures() {
+        let failures = Failures::default();
+        let runs = Arc::new(AtomicU64::new(0));
+        let (inner, counted) = (failures.clone(), runs.clone());
+        //A hook may replace itself, and a panicking hook leaves the next report working
+        failures.set(move |_, _| {
+            let counted = counted.clone();
+            inner.set(move |_, _| {counted.fetch_add(1, Ordering::SeqCst);});
+            panic!("hook");
+        });
+        let error: TaskError = "failed".into();
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| failures.report("task", &error))).is_err());
+        failures.report("task", &error);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn backoff() {
+        let retry = Retry::exponential(5, Duration::from_millis(100), Duration::from_secs(1));
+        let delays = (0..5).map(|attempt| retry.backoff(attempt).as_millis()).collect::<Vec<_>>();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000]);
+        assert_eq!(retry.backoff(u32::MAX), Duration::from_secs(1));
+        let jitter = retry.with_jitter();
+        (0..5).for_each(|attempt| {
+            let delay = jitter.backoff(attempt);
+            let max = Duration::from_millis(100 * 2u64.pow(attempt)).min(Duration::from_secs(1));
+            assert!(delay >= max / 2 && delay <= max, "{:?}", delay);
+        });
     }
 }
@@ -0,0 +1,171 @@
+use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::str::FromStr;
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+//Expressions that never match (Feb 30th) give up after searching this many days
+const SEARCH_DAYS: u64 = 366 * 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidCron(pub String);
+
+impl std::fmt::Display for InvalidCron {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Invalid cron expression: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidCron {}
+
+///Cron schedule in UTC, five fields: minute hour day-of-month month day-of-week
+///Fields accept `*`, values, ranges `a-b`, steps `*/n` or `a-b/n` and lists `a,b`
+///Sunday is 0 or 7, when both day fields are restricted either one matching is enough
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool
+}
+
+impl Cron {
+    pub fn new(expression: &str) -> Result<Self, InvalidCron> {
+        let invalid = || InvalidCron(expression.to_string());
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {return Err(invalid());}
+        let field = |i: usize, min: u64, max: u64| Self::field(fields[i], min, max).ok_or_else(invalid);
+        let weekdays = field(4, 0, 7)?;
+        Ok(Cron{
+            minutes: field(0, 0, 59)?,
+            hours: field(1, 0, 23)?,
+            days: field(2, 1, 31)?,
+            months: field(3, 1, 12)?,
+            //7 is also Sunday
+            weekdays: (weekdays | (weekdays >> 7)) & 0x7f,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*"
+        })
+    }
+
+    ///The first minute matching the schedule after the given time
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let mut minute = time.duration_since(UNIX_EPOCH).ok()?.as_secs() / 60 + 1;
+        let end = minute / MINUTES_PER_DAY + SEARCH_DAYS;
+        while minute / MINUTES_PER_DAY < end {
+            let days = minute / MINUTES_PER_DAY;
+            let (month, day) = Self::civil(days);
+            let weekday = (days + 4) % 7;//1970-01-01 was a Thursday
+            if !self.matches_day(month, day, weekday) {
+                minute = (days + 1) * MINUTES_PER_DAY;
+            } else if self.hours & (1 << ((minute % MINUTES_PER_DAY) / 60)) == 0 {
+                minute = (minute / 60 + 1) * 60;
+            } else if self.minutes & (1 << (minute % 60)) == 0 {
+                minute += 1;
+            } else {
+                return Some(UNIX_EPOCH + Duration::from_secs(minute * 60));
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, month: u64, day: u64, weekday: u64) -> bool {
+        if self.months & (1 << month) == 0 {return false;}
+        let day = self.days & (1 << day) != 0;
+        let weekday = self.weekdays & (1 << weekday) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday
+        }
+    }
+
+    fn field(field: &str, min: u64, max: u64) -> Option<u64> {
+        field.split(',').try_fold(0, |mask, part| {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, u64::from_str(step).ok().filter(|s| *s > 0)?),
+                None => (part, 1)
+            };
+            let (start, end) = match range {
+                "*" => (min, max),
+                range => match range.split_once('-') {
+                    Some((start, end)) => (u64::from_str(start).ok()?, u64::from_str(end).ok()?),
+                    None => {
+                        let value = u64::from_str(range).ok()?;
+                        (value, if step > 1 {max} else {value})
+                    }
+                }
+            };
+            if start < min || end > max || start > end {return None;}
+            Some((start..=end).step_by(step as usize).fold(mask, |mask, v| mask | (1 << v)))
+        })
+    }
+
+    //Month and day of the month for days since 1970-01-01
+    fn civil(days: u64) -> (u64, u64) {
+        let z = days + 719468;
+        let doe = z % 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 {mp + 3} else {mp - 9};
+        (month, day)
+    }
+}
+
+impl FromStr for Cron {
+    type Err = InvalidCron;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {Cron::new(s)}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {UNIX_EPOCH + Duration::from_secs(secs)}
+
+    #[test]
+    fn field() {
+        assert_eq!(Cron::field("*/15", 0, 59), Some(1 | 1 << 15 | 1 << 30 | 1 << 45));
+        assert_eq!(Cron::field("1-3,5", 0, 59), Some(0b101110));
+        assert_eq!(Cron::field("50/5", 0, 59), Some(1 << 50 | 1 << 55));
+        assert_eq!(Cron::field("10-20/5", 0, 59), Some(1 << 10 | 1 << 15 | 1 << 20));
+        for invalid in ["60", "5-1", "*/0", "a", "1-", ""] {
+            assert_eq!(Cron::field(invalid, 0, 59), None, "{}", invalid);
+        }
+        assert_eq!(Cron::field("0", 1, 31), None);
+    }
+
+    #[test]
+    fn new() {
+        assert!(Cron::new("* * * *").is_err());
+        assert!(Cron::new("* * * * * *").is_err());
+        assert!(Cron::new("0 24 * * *").is_err());
+        assert_eq!(Cron::new("0 0 * * 7"), Cron::new("0 0 * * 0"));
+        assert_eq!("*/5 * * * *".parse::<Cron>(), Cron::new("*/5 * * * *"));
+    }
+
+    #[test]
+    fn civil() {
+        assert_eq!(Cron::civil(0), (1, 1));
+        assert_eq!(Cron::civil(364), (12, 31));
+        assert_eq!(Cron::civil(19782), (2, 29));//2024-02-29
+        assert_eq!(Cron::civil(11017), (3, 1));//2000-03-01
+    }
+
+    #[test]
+    fn next_after() {
+        //2025-01-01 00:00 was a Wednesday
+        let new_year = at(1735689600);
+        assert_eq!(Cron::new("0 9 * * 1").unwrap().next_after(new_year), Some(at(1736154000)));
+        assert_eq!(Cron::new("30 * * * *").unwrap().next_after(at(1735727400)), Some(at(1735731000)));
+        assert_eq!(Cron::new("* * * * *").unwrap().next_after(new_year), Some(at(1735689600 + 60)));
+        //Either day field matching is enough, Friday the 3rd comes before the 13th
+        assert_eq!(Cron::new("0 0 13 * 5").unwrap().next_after(new_year), Some(at(1735862400)));
+        assert_eq!(Cron::new("0 0 29 2 *").unwrap().next_after(new_year).map(|time| Cron::civil(
+            time.duration_since(UNIX_EPOCH).unwrap().as_secs() / 60 / MINUTES_PER_DAY
+        )), Some((2, 29)));
+        assert_eq!(Cron::new("0 0 30 2 *").unwrap().next_after(new_year), None);
+    }
+}
@@ -1,28 +1,587 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{OnceLock, RwLock, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::cell::{RefCell, OnceCell};
+use std::any::{Any, TypeId};
+
+mod history;
+use history::{History, Change};
 use std::fmt::Debug;
 
 use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+///Upgrades data stored by one version of a Field to the next
+pub type Migration = fn(Value) -> Result<Value, String>;
+
+///How a Field is stored, JSON is larger but readable when debugging
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    Json,
+    #[default]
+    Binary
+}
+
+//Binary data starts with a byte JSON never starts with, followed by the version
+const BINARY_TAG: u8 = 0;
+
+#[derive(Debug)]
+pub enum FieldError {
+    Decode(serde_json::Error),
+    DecodeBinary(rmp_serde::decode::Error),
+    Migration{version: usize, error: String},
+    ///The data was stored by a newer version than this build knows
+    UnknownVersion{found: usize, current: usize}
+}
+
+impl std::fmt::Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FieldError::Decode(e) => write!(f, "Field could not be decoded: {}", e),
+            FieldError::DecodeBinary(e) => write!(f, "Field could not be decoded: {}", e),
+            FieldError::Migration{version, error} => write!(f, "Field migration from version {} failed: {}", version, error),
+            FieldError::UnknownVersion{found, current} => write!(f, "Field version {} is newer than {}", found, current)
+        }
+    }
+}
+
+impl std::error::Error for FieldError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FieldError::Decode(e) => Some(e),
+            FieldError::DecodeBinary(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<serde_json::Error> for FieldError {
+    fn from(e: serde_json::Error) -> Self {FieldError::Decode(e)}
+}
+
+impl From<rmp_serde::decode::Error> for FieldError {
+    fn from(e: rmp_serde::decode::Error) -> Self {FieldError::DecodeBinary(e)}
+}
+
+//Migrations and encodings registered by type, shared by State and Cache
+#[derive(Default)]
+struct Schema {
+    migrations: Vec<Migration>,
+    encoding: Encoding,
+    used: AtomicBool
+}
+
+//Schemas live for the whole process so reads borrow them instead of cloning
+fn schema(ident: &'static str) -> &'static Schema {
+    let schema = schemas().read().unwrap_or_else(PoisonError::into_inner).get(ident).copied();
+    let schema = schema.unwrap_or_else(|| *schemas().write().unwrap_or_else(PoisonError::into_inner).entry(ident).or_insert_with(|| Box::leak(Box::default())));
+    schema.used.store(true, Ordering::Relaxed);
+    schema
+}
+
+//Data read or written before registering would be stamped with the wrong version
+fn register(ident: &'static str, update: impl FnOnce(&mut Schema)) {
+    let mut schemas = schemas().write().unwrap_or_else(PoisonError::into_inner);
+    let mut schema = Schema::default();
+    if let Some(registered) = schemas.get(ident) {
+        assert!(!registered.used.load(Ordering::Relaxed), "{} was registered after it was encoded or decoded", ident);
+        schema = Schema{migrations: registered.migrations.clone(), encoding: registered.encoding, used: AtomicBool::new(false)};
+    }
+    update(&mut schema);
+    schemas.insert(ident, Box::leak(Box::new(schema)));
+}
+
+fn schemas() -> &'static RwLock<HashMap<&'static str, &'static Schema>> {
+    static SCHEMAS: OnceLock<RwLock<HashMap<&'static str, &'static Schema>>> = OnceLock::new();
+    SCHEMAS.get_or_init(RwLock::default)
+}
 
 pub trait Field: Serialize + for<'a> Deserialize <'a> + Default + Debug {
-    fn ident() -> String where Self: Sized + 'static {
+    fn ident() -> String where Self: Sized {
         std::any::type_name::<Self>().to_string()
     }
-    fn to_bytes(&self) -> Vec<u8> {serde_json::to_vec(self).unwrap()}
-    fn from_bytes(bytes: &[u8]) -> Self where Self: Sized {
-        serde_json::from_slice(bytes).unwrap_or_default()
+    ///Versions the stored data, register in the app's register_fields before the data is read.
+    ///The migration at index n upgrades version n to n + 1, the current version is the number of migrations.
+    ///Data stored before a type was versioned is version 0
+    fn register_migrations(migrations: Vec<Migration>) where Self: Sized {
+        register(std::any::type_name::<Self>(), |schema| schema.migrations = migrations);
+    }
+    ///Binary by default, data in either encoding is read back so changing it only affects new writes
+    fn register_encoding(encoding: Encoding) where Self: Sized {
+        register(std::any::type_name::<Self>(), |schema| schema.encoding = encoding);
+    }
+    fn to_bytes(&self) -> Vec<u8> where Self: Sized {
+        let schema = schema(std::any::type_name::<Self>());
+        let version = schema.migrations.len();
+        match schema.encoding {
+            Encoding::Binary => {
+                let mut bytes = vec![BINARY_TAG];
+                bytes.extend((version as u32).to_le_bytes());
+                rmp_serde::encode::write_named(&mut bytes, self).unwrap();
+                bytes
+            },
+            Encoding::Json if version == 0 => serde_json::to_vec(self).unwrap(),
+            Encoding::Json => serde_json::to_vec(&serde_json::json!({"$version": version, "$data": self})).unwrap()
+        }
+    }
+    fn from_bytes(bytes: &[u8]) -> Result<Self, FieldError> where Self: Sized {
+        let migrations = &schema(std::any::type_name::<Self>()).migrations;
+        let (version, data) = match bytes {
+            [BINARY_TAG, v0, v1, v2, v3, data @ ..] => {
+                let version = u32::from_le_bytes([*v0, *v1, *v2, *v3]) as usize;
+                //Current data skips the detour through a Value
+                if version == migrations.len() {return Ok(rmp_serde::from_slice(data)?);}
+                (version, rmp_serde::from_slice(data)?)
+            },
+            bytes => match serde_json::from_slice(bytes)? {
+                Value::Object(mut map) if map.len() == 2 && map.contains_key("$data") => match map.get("$version").and_then(Value::as_u64) {
+                    Some(version) => (version as usize, map.remove("$data").unwrap()),
+                    None => (0, Value::Object(map))
+                },
+                value => (0, value)
+            }
+        };
+        if version > migrations.len() {
+            return Err(FieldError::UnknownVersion{found: version, current: migrations.len()});
+        }
+        let data = migrations[version..].iter().enumerate().try_fold(data, |data, (i, migration)|
+            migration(data).map_err(|error| FieldError::Migration{version: version + i, error})
+        )?;
+        Ok(serde_json::from_value(data)?)
     }
 }
 
 impl<I: Serialize + for<'a> Deserialize <'a> + Default + Debug> Field for I {}
 
+//A value with what it takes to serialize it without knowing its type
+struct Entry {
+    value: Box<dyn Any>,
+    ident: String,
+    to_bytes: fn(&dyn Any) -> Vec<u8>
+}
+
+impl Entry {
+    fn new<F: Field + 'static>(item: F) -> Self {
+        Entry{
+            value: Box::new(item),
+            ident: F::ident(),
+            to_bytes: |value| value.downcast_ref::<F>().unwrap().to_bytes()
+        }
+    }
+}
+
+//A restored field, decoded once by its first typed read
+struct Pending {
+    bytes: Vec<u8>,
+    decoded: OnceCell<Entry>
+}
+
+///Typed values keyed by type, they are only serialized for snapshots
+#[derive(Default)]
+pub struct State {
+    fields: HashMap<TypeId, Entry>,
+    //Restored fields that have not been read as their type yet
+    pending: HashMap<String, Pending>,
+    //Bumped whenever a field is set, restoring bumps the epoch to change every field
+    versions: HashMap<TypeId, u64>,
+    epoch: u64,
+    //Fields read since tracking started
+    reads: RefCell<Option<HashSet<TypeId>>>,
+    persisted: HashSet<String>,
+    history: History
+}
 
-#[derive(Debug, Default, Serialize, Deserialize)]
-pub struct State(HashMap<String, Vec<u8>>);
 impl State {
-    pub fn set<F: Field + 'static>(&mut self, item: &F) {
-        self.0.insert(F::ident(), item.to_bytes());
+    ///Every set counts as a change
+    pub fn set<F: Field + 'static>(&mut self, item: F) {
+        let id = TypeId::of::<F>();
+        //Undoing the first set of a restored field goes back to the restored value
+        let restored = match self.history.recorded.contains_key(&id) && !self.fields.contains_key(&id) {
+            true => self.take_pending::<F>().unwrap_or_else(|e| {
+                log::error!("State {} could not be restored: {}", F::ident(), e);
+                None
+            }).map(Entry::new),
+            false => {self.pending.remove(&F::ident()); None}
+        };
+        let before = self.fields.insert(id, Entry::new(item)).or(restored);
+        self.changed(id, || before);
+    }
+
+    ///A clone of the field, the default when it was never set or can't be restored
+    pub fn get<F: Field + Clone + 'static>(&self) -> F {
+        self.try_get().unwrap_or_else(|e| {
+            log::error!("State {} could not be restored: {}", F::ident(), e);
+            F::default()
+        })
+    }
+
+    ///Like `get` but surfaces restored data that can't be decoded
+    pub fn try_get<F: Field + Clone + 'static>(&self) -> Result<F, FieldError> {
+        Ok(self.field::<F>()?.cloned().unwrap_or_default())
+    }
+
+    ///Borrows the field, None when it was never set or can't be restored
+    pub fn get_ref<F: Field + 'static>(&self) -> Option<&F> {
+        self.field::<F>().unwrap_or_else(|e| {
+            log::error!("State {} could not be restored: {}", F::ident(), e);
+            None
+        })
+    }
+
+    fn field<F: Field + 'static>(&self) -> Result<Option<&F>, FieldError> {
+        self.read(TypeId::of::<F>());
+        if let Some(entry) = self.fields.get(&TypeId::of::<F>()) {return Ok(entry.value.downcast_ref());}
+        let Some(pending) = self.pending.get(&F::ident()) else {return Ok(None);};
+        if pending.decoded.get().is_none() {
+            let _ = pending.decoded.set(Entry::new(F::from_bytes(&pending.bytes)?));
+        }
+        Ok(pending.decoded.get().and_then(|entry| entry.value.downcast_ref()))
+    }
+
+    ///Mutable access counts as a change
+    pub fn get_mut<F: Field + 'static>(&mut self) -> &mut F {
+        let id = TypeId::of::<F>();
+        self.read(id);
+        if !self.fields.contains_key(&id) {
+            //Decoding a restored field or starting from the default is not a change, only the mutation is
+            let item = self.take_pending::<F>().unwrap_or_else(|e| {
+                log::error!("State {} could not be restored: {}", F::ident(), e);
+                None
+            }).unwrap_or_default();
+            self.fields.insert(id, Entry::new(item));
+        }
+        let clone = self.history.recorded.get(&id).copied();
+        let before = clone.map(|clone| clone(&self.fields[&id]));
+        self.changed(id, || before);
+        self.fields.get_mut(&id).unwrap().value.downcast_mut().unwrap()
     }
-    pub fn get<F: Field + 'static>(&self) -> F {
-        self.0.get(&F::ident()).map(|b| F::from_bytes(b)).unwrap_or_default()
+
+    ///Serializes every field by its ident
+    pub fn snapshot(&self) -> HashMap<String, Vec<u8>> {
+        let mut snapshot = self.pending_bytes();
+        snapshot.extend(self.fields.values().map(|entry| (entry.ident.clone(), (entry.to_bytes)(&*entry.value))));
+        snapshot
+    }
+
+    ///Replaces every field with the snapshot, fields are decoded once they are read
+    pub fn restore(&mut self, snapshot: HashMap<String, Vec<u8>>) {
+        self.fields.clear();
+        self.pending = snapshot.into_iter().map(|(ident, bytes)| (ident, Pending{bytes, decoded: OnceCell::new()})).collect();
+        self.epoch += 1;
+        self.history.clear();
+    }
+
+    ///Restores the snapshot's fields that were not set yet, keeping the ones that were
+    pub fn merge(&mut self, snapshot: HashMap<String, Vec<u8>>) {
+        let live = self.fields.values().map(|entry| entry.ident.clone()).collect::<HashSet<_>>();
+        self.pending.extend(snapshot.into_iter().filter(|(ident, _)| !live.contains(ident)).map(|(ident, bytes)|
+            (ident, Pending{bytes, decoded: OnceCell::new()})
+        ));
+        self.epoch += 1;
+    }
+
+    ///Records changes to the field so they can be undone
+    pub fn record<F: Field + Clone + 'static>(&mut self) {
+        self.history.recorded.insert(TypeId::of::<F>(), |entry|
+            Entry::new(entry.value.downcast_ref::<F>().unwrap().clone())
+        );
+    }
+
+    ///Most steps that can be undone, 100 by default
+    pub fn set_history_limit(&mut self, limit: usize) {self.history.limit = limit;}
+
+    ///Changes until `end_group` are undone and redone as one step
+    pub fn begin_group(&mut self) {self.history.begin_group();}
+    pub fn end_group(&mut self) {
+        self.history.end_group();
+        self.bump(TypeId::of::<History>());
+    }
+    pub fn group(&mut self, f: impl FnOnce(&mut State)) {
+        self.begin_group();
+        f(self);
+        self.end_group();
+    }
+
+    ///Reverts the last step, returning whether there was one
+    pub fn undo(&mut self) -> bool {
+        let Some(changes) = self.history.undo() else {return false;};
+        let changes = self.apply(changes);
+        self.history.push_redo(changes);
+        true
+    }
+
+    ///Reapplies the last undone step, returning whether there was one
+    pub fn redo(&mut self) -> bool {
+        let Some(changes) = self.history.redo() else {return false;};
+        let changes = self.apply(changes);
+        self.history.push_undo(changes);
+        true
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.read(TypeId::of::<History>());
+        self.history.can_undo()
+    }
+    pub fn can_redo(&self) -> bool {
+        self.read(TypeId::of::<History>());
+        self.history.can_redo()
+    }
+
+    //Puts back the values before the changes, returning the changes that reverse it
+    fn apply(&mut self, changes: Vec<Change>) -> Vec<Change> {
+        self.bump(TypeId::of::<History>());
+        changes.into_iter().rev().map(|Change(id, before)| {
+            self.bump(id);
+            let after = match before {
+                Some(entry) => self.fields.insert(id, entry),
+                None => self.fields.remove(&id)
+            };
+            Change(id, after)
+        }).collect()
+    }
+
+    //Bumps the version and records the value before the change for recorded fields
+    fn changed(&mut self, id: TypeId, before: impl FnOnce() -> Option<Entry>) {
+        self.bump(id);
+        if self.history.recorded.contains_key(&id) {
+            self.history.push(Change(id, before()));
+            self.bump(TypeId::of::<History>());
+        }
+    }
+
+    fn bump(&mut self, id: TypeId) {*self.versions.entry(id).or_default() += 1;}
+
+    fn read(&self, id: TypeId) {
+        if let Some(reads) = self.reads.borrow_mut().as_mut() {reads.insert(id);}
+    }
+
+    ///Saves the field to the Cache when the app pauses or closes, it is restored on the next start
+    pub fn persist<F: Field + 'static>(&mut self) {self.persisted.insert(F::ident());}
+
+    ///Snapshot of the persisted fields, restored fields not changed yet are kept as they are
+    pub(crate) fn persisted(&self) -> HashMap<String, Vec<u8>> {
+        let mut snapshot = self.pending_bytes();
+        snapshot.extend(self.fields.values().filter(|entry| self.persisted.contains(&entry.ident)).map(|entry|
+            (entry.ident.clone(), (entry.to_bytes)(&*entry.value))
+        ));
+        snapshot
+    }
+
+    fn pending_bytes(&self) -> HashMap<String, Vec<u8>> {
+        self.pending.iter().map(|(ident, pending)| (ident.clone(), pending.bytes.clone())).collect()
+    }
+
+    //Moves a restored field out of pending, reusing the value decoded by a read
+    fn take_pending<F: Field + 'static>(&mut self) -> Result<Option<F>, FieldError> {
+        let Some(pending) = self.pending.remove(&F::ident()) else {return Ok(None);};
+        match pending.decoded.into_inner().and_then(|entry| entry.value.downcast::<F>().ok()) {
+            Some(item) => Ok(Some(*item)),
+            None => F::from_bytes(&pending.bytes).map(Some)
+        }
+    }
+
+    ///Watches the field for changes from now on
+    pub fn subscribe<F: Field + 'static>(&self) -> StateSubscription {
+        let id = TypeId::of::<F>();
+        StateSubscription{version: self.version(id), id}
+    }
+
+    fn version(&self, id: TypeId) -> u64 {self.versions.get(&id).copied().unwrap_or_default() + self.epoch}
+
+    ///Records the fields read with `get` until `dependencies` is called
+    pub(crate) fn track(&self) {*self.reads.borrow_mut() = Some(HashSet::new());}
+
+    ///Subscriptions to every field read since `track`
+    pub(crate) fn dependencies(&self) -> Vec<StateSubscription> {
+        self.reads.borrow_mut().take().unwrap_or_default().into_iter().map(|id|
+            StateSubscription{version: self.version(id), id}
+        ).collect()
+    }
+}
+
+impl Debug for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("State")
+            .field("fields", &self.fields.values().map(|entry| &entry.ident).collect::<Vec<_>>())
+            .field("pending", &self.pending.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Serialize for State {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.snapshot().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for State {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut state = State::default();
+        state.restore(HashMap::deserialize(deserializer)?);
+        Ok(state)
+    }
+}
+
+///Notifies about changes to a field in State, see `State::subscribe`
+#[derive(Debug, Clone)]
+pub struct StateSubscription {
+    id: TypeId,
+    version: u64
+}
+
+impl StateSubscription {
+    ///Whether the field changed since the last call
+    pub fn changed(&mut self, state: &State) -> bool {
+        let version = state.version(self.id);
+        let changed = version != self.version;
+        self.version = version;
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+    struct Before {name: String}
+
+    #[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+    struct After {name: String, age: u32}
+
+    #[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+    struct Unversioned {name: String, age: u32}
+
+    #[test]
+    fn migrations() {
+        After::register_migrations(vec![|mut value| {
+            value.as_object_mut().ok_or("not an object")?.insert("age".to_string(), 7.into());
+            Ok(value)
+        }]);
+        let after = After{name: "a".to_string(), age: 7};
+        assert_eq!(After::from_bytes(&Before{name: "a".to_string()}.to_bytes()).unwrap(), after);
+        assert_eq!(After::from_bytes(br#"{"name":"a"}"#).unwrap(), after);
+        assert_eq!(After::from_bytes(&after.to_bytes()).unwrap(), after);
+        assert!(matches!(After::from_bytes(b"[1]"), Err(FieldError::Migration{version: 0, ..})));
+        assert!(matches!(After::from_bytes(b"{"), Err(FieldError::Decode(_))));
+        assert!(matches!(Unversioned::from_bytes(&after.to_bytes()), Err(FieldError::UnknownVersion{found: 1, current: 0})));
+        //Required fields missing from unversioned data are an error, not a default
+        assert!(Unversioned::from_bytes(&Before{name: "a".to_string()}.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn encoding() {
+        #[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+        struct Readable(Vec<u8>);
+        Readable::register_encoding(Encoding::Json);
+        let readable = Readable(vec![1, 2]);
+        assert_eq!(readable.to_bytes(), b"[1,2]");
+        assert_eq!(Readable::from_bytes(&readable.to_bytes()).unwrap(), readable);
+        assert_eq!(Vec::<u8>::from_bytes(&vec![1u8, 2].to_bytes()).unwrap(), vec![1, 2]);
+        assert_eq!(vec![1u8, 2].to_bytes()[0], BINARY_TAG);
+    }
+
+    #[test]
+    #[should_panic(expected = "registered after it was encoded or decoded")]
+    fn late_registration() {
+        #[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+        struct Late(u8);
+        Late(1).to_bytes();
+        Late::register_migrations(vec![Ok]);
+    }
+
+    #[test]
+    fn undo_redo() {
+        let mut state = State::default();
+        state.record::<u8>();
+        state.set(1u8);
+        state.set(2u8);
+        *state.get_mut::<u8>() += 1;
+        state.set(5u16);
+        assert!(state.undo());
+        assert_eq!(state.get::<u8>(), 2);
+        assert!(state.undo());
+        assert!(state.undo());
+        assert_eq!(state.get_ref::<u8>(), None);
+        assert!(!state.undo());
+        assert!(state.redo());
+        assert_eq!(state.get::<u8>(), 1);
+        //A new change drops what could be redone, fields not recorded are left alone
+        state.set(9u8);
+        assert!(!state.can_redo());
+        assert_eq!(state.get::<u16>(), 5);
+    }
+
+    #[test]
+    fn groups() {
+        let mut state = State::default();
+        state.record::<u8>();
+        state.record::<String>();
+        state.group(|state| {
+            state.set(1u8);
+            state.group(|state| state.set("a".to_string()));
+            state.set(2u8);
+        });
+        assert!(state.undo());
+        assert_eq!((state.get_ref::<u8>(), state.get_ref::<String>()), (None, None));
+        assert!(!state.can_undo());
+        assert!(state.redo());
+        assert_eq!((state.get::<u8>(), state.get::<String>()), (2, "a".to_string()));
+    }
+
+    #[test]
+    fn history_limit() {
+        let mut state = State::default();
+        state.record::<u8>();
+        state.set_history_limit(2);
+        (0..5u8).for_each(|i| state.set(i));
+        assert!(state.undo() && state.undo());
+        assert!(!state.undo());
+        assert_eq!(state.get::<u8>(), 2);
+    }
+
+    #[test]
+    fn restored_history() {
+        let mut saved = State::default();
+        saved.set("doc".to_string());
+        let mut state = State::default();
+        state.record::<String>();
+        state.restore(saved.snapshot());
+        assert_eq!(state.get_ref::<String>().map(String::as_str), Some("doc"));
+        state.get_mut::<String>().push('!');
+        assert!(state.undo());
+        assert_eq!(state.get::<String>(), "doc");
+        assert!(!state.undo());
+
+        state.restore(saved.snapshot());
+        state.set("new".to_string());
+        assert!(state.undo());
+        assert_eq!(state.get::<String>(), "doc");
+    }
+
+    #[test]
+    fn merge() {
+        let mut saved = State::default();
+        saved.set("doc".to_string());
+        saved.set(1u8);
+        let mut state = State::default();
+        state.set(2u8);
+        let mut subscription = state.subscribe::<String>();
+        state.merge(saved.snapshot());
+        assert!(subscription.changed(&state));
+        assert_eq!(state.get::<String>(), "doc");
+        assert_eq!(state.get::<u8>(), 2);
+    }
+
+    #[test]
+    fn dependencies() {
+        let mut state = State::default();
+        state.track();
+        *state.get_mut::<u8>() += 1;
+        state.get::<u16>();
+        let mut dependencies = state.dependencies();
+        assert_eq!(dependencies.len(), 2);
+        state.set(2u8);
+        assert_eq!(dependencies.iter_mut().map(|d| d.changed(&state)).filter(|changed| *changed).count(), 1);
     }
 }
@@ -0,0 +1,67 @@
+use std::collections::{HashMap, VecDeque};
+use std::any::TypeId;
+
+use super::Entry;
+
+const HISTORY_LIMIT: usize = 100;
+
+//A field and its value before the change, None when it was not set
+pub(super) struct Change(pub TypeId, pub Option<Entry>);
+
+pub(super) struct History {
+    //Clones a recorded field, keeping its value before a change through get_mut
+    pub recorded: HashMap<TypeId, fn(&Entry) -> Entry>,
+    pub limit: usize,
+    undo: VecDeque<Vec<Change>>,
+    redo: Vec<Vec<Change>>,
+    group: Option<Vec<Change>>,
+    depth: usize
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History{recorded: HashMap::new(), limit: HISTORY_LIMIT, undo: VecDeque::new(), redo: vec![], group: None, depth: 0}
+    }
+}
+
+impl History {
+    ///Records a new change, which clears what could be redone
+    pub fn push(&mut self, change: Change) {
+        self.redo.clear();
+        match &mut self.group {
+            Some(group) => group.push(change),
+            None => self.push_undo(vec![change])
+        }
+    }
+
+    pub fn push_undo(&mut self, changes: Vec<Change>) {
+        if changes.is_empty() {return;}
+        self.undo.push_back(changes);
+        while self.undo.len() > self.limit {self.undo.pop_front();}
+    }
+    pub fn push_redo(&mut self, changes: Vec<Change>) {self.redo.push(changes);}
+
+    pub fn undo(&mut self) -> Option<Vec<Change>> {self.undo.pop_back()}
+    pub fn redo(&mut self) -> Option<Vec<Change>> {self.redo.pop()}
+
+    pub fn can_undo(&self) -> bool {!self.undo.is_empty()}
+    pub fn can_redo(&self) -> bool {!self.redo.is_empty()}
+
+    ///Groups nest, the outermost group becomes one step
+    pub fn begin_group(&mut self) {
+        self.depth += 1;
+        self.group.get_or_insert_with(Vec::new);
+    }
+    pub fn end_group(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+        if self.depth == 0 {
+            if let Some(group) = self.group.take() {self.push_undo(group);}
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.group = self.group.as_ref().map(|_| vec![]);
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::{Arc, Mutex};
+
+use super::cache::lock;
+use std::collections::HashMap;
+use std::cell::RefCell;
+use std::any::{Any, TypeId};
+use std::fmt::Debug;
+
+use tokio::sync::watch;
+
+//Each type has its own lock so closures can read other values, the map is only locked to find it.
+//Slots are never removed so a write can't land in a slot that is no longer in the map
+type Slot = Arc<Mutex<Option<Box<dyn Any + Send + Sync>>>>;
+
+#[derive(Default)]
+struct Inner {
+    values: Mutex<HashMap<TypeId, Slot>>,
+    watchers: Mutex<HashMap<TypeId, watch::Sender<()>>>,
+    //Bumped by every write of the type, lets the UI notice changes to what it read without subscribing
+    versions: Mutex<HashMap<TypeId, u64>>
+}
+
+thread_local! {
+    //Versions of the values read on this thread since tracking started, the UI tracks its builds
+    static READS: RefCell<Option<HashMap<TypeId, u64>>> = const {RefCell::new(None)};
+}
+
+///Live values shared between tasks and the UI, one per type, nothing is written to the Cache
+#[derive(Clone, Default)]
+pub struct Store(Arc<Inner>);
+
+impl Debug for Store {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Store").field("types", &lock(&self.0.values).len()).finish()
+    }
+}
+
+impl Store {
+    pub fn set<T: Send + Sync + 'static>(&self, value: T) {
+        *lock(&self.slot::<T>()) = Some(Box::new(value));
+        self.notify::<T>();
+    }
+
+    pub fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {self.with(|value: Option<&T>| value.cloned())}
+
+    ///Reads the value in place, only this type is locked until f returns
+    pub fn with<T: Send + Sync + 'static, R>(&self, f: impl FnOnce(Option<&T>) -> R) -> R {
+        READS.with_borrow_mut(|reads| if let Some(reads) = reads {
+            reads.entry(TypeId::of::<T>()).or_insert_with(|| self.version(TypeId::of::<T>()));
+        });
+        let slot = self.slot::<T>();
+        let value = lock(&slot);
+        f(value.as_ref().map(|value| value.downcast_ref().unwrap()))
+    }
+
+    ///Changes the value in place starting from the default, only this type is locked until f returns
+    pub fn update<T: Default + Send + Sync + 'static, R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let slot = self.slot::<T>();
+        let result = f(lock(&slot).get_or_insert_with(|| Box::new(T::default())).downcast_mut().unwrap());
+        self.notify::<T>();
+        result
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&self) -> Option<T> {
+        let value = lock(&self.slot::<T>()).take();
+        value.map(|value| {
+            self.notify::<T>();
+            *value.downcast().unwrap()
+        })
+    }
+
+    fn slot<T: 'static>(&self) -> Slot {lock(&self.0.values).entry(TypeId::of::<T>()).or_default().clone()}
+
+    ///Watches for writes and removals of the value
+    pub fn subscribe<T: Send + Sync + 'static>(&self) -> StoreSubscription {
+        StoreSubscription(lock(&self.0.watchers).entry(TypeId::of::<T>()).or_insert_with(|| watch::channel(()).0).subscribe())
+    }
+
+    ///Records the values read on this thread until `dependencies` is called
+    pub(crate) fn track() {READS.set(Some(HashMap::new()));}
+
+    ///Versions of every value read on this thread since `track`
+    pub(crate) fn dependencies() -> HashMap<TypeId, u64> {READS.take().unwrap_or_default()}
+
+    ///Whether any of the values was written since the dependencies were taken
+    pub(crate) fn changed(&self, dependencies: &HashMap<TypeId, u64>) -> bool {
+        dependencies.iter().any(|(id, version)| self.version(*id) != *version)
+    }
+
+    fn version(&self, id: TypeId) -> u64 {lock(&self.0.versions).get(&id).copied().unwrap_or_default()}
+
+    fn notify<T: 'static>(&self) {
+        *lock(&self.0.versions).entry(TypeId::of::<T>()).or_default() += 1;
+        if let Some(sender) = lock(&self.0.watchers).get(&TypeId::of::<T>()) {sender.send_replace(());}
+    }
+}
+
+///Notifies about changes to a value in the Store, see `Store::subscribe`
+#[derive(Debug)]
+pub struct StoreSubscription(watch::Receiver<()>);
+
+impl StoreSubscription {
+    ///Whether the value changed since the last call, cheap enough to check every frame
+    pub fn changed(&mut self) -> bool {
+        let changed = self.0.has_changed().unwrap_or_default();
+        self.0.borrow_and_update();
+        changed
+    }
+
+    ///Waits for the next change
+    pub async fn wait(&mut self) {
+        if self.0.changed().await.is_err() {std::future::pending::<()>().await}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values() {
+        let store = Store::default();
+        let mut subscription = store.subscribe::<u8>();
+        assert_eq!(store.get::<u8>(), None);
+        store.set(1u8);
+        assert_eq!(store.update(|value: &mut u8| {*value += 1; *value}), 2);
+        assert!(subscription.changed());
+        assert!(!subscription.changed());
+        //Other values can be read while one is locked
+        store.set("a".to_string());
+        assert_eq!(store.with(|value: Option<&u8>| (value.copied(), store.get::<String>())), (Some(2), Some("a".to_string())));
+        assert_eq!(store.remove::<u8>(), Some(2));
+        assert_eq!(store.remove::<u8>(), None);
+        assert!(subscription.changed());
+    }
+
+    #[test]
+    fn poisoned() {
+        let store = Store::default();
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| store.update(|_: &mut u8| panic!())));
+        assert!(panicked.is_err());
+        store.set(1u8);
+        assert_eq!(store.get::<u8>(), Some(1));
+    }
+
+    #[test]
+    fn dependencies() {
+        let store = Store::default();
+        Store::track();
+        store.get::<u8>();
+        let dependencies = Store::dependencies();
+        store.set(1u16);
+        assert!(!store.changed(&dependencies));
+        store.set(1u8);
+        assert!(store.changed(&dependencies));
+    }
+}
@@ -168,7 +168,10 @@ impl Renderer for Canvas {
         };
         app.on_event(r_event).await;
         let ctx = app.ctx();
-        if draw {self.draw(&mut ctx.image, &mut ctx.font, ctx.components.drain(..).collect::<Vec<_>>());}
+        //Nothing drawn this tick keeps the last frame on screen
+        if draw && !ctx.components.is_empty() {
+            self.draw(&mut ctx.image, &mut ctx.font, ctx.components.drain(..).collect::<Vec<_>>());
+        }
     }
 
     async fn close(self, _ctx: Self::Context) {}
@@ -1,6 +1,8 @@
 use std::future::Future;
 use std::path::PathBuf;
-use std::sync::{Mutex, Arc};
+use std::sync::Arc;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 use winit_crate::event_loop::{ActiveEventLoop, ControlFlow, EventLoop};
 use winit_crate::event::{ElementState, WindowEvent as WinitWindowEvent, TouchPhase, Touch, MouseScrollDelta};
@@ -14,38 +16,49 @@ use winit_crate::platform::android::EventLoopBuilderExtAndroid;
 
 use super::{WindowAppTrait, WindowEvent, MouseState, KeyboardState};
 
-#[derive(Default)]
-pub struct BlockingFuture<T: 'static>(Arc<Mutex<Option<T>>>);
-impl<T: 'static> BlockingFuture<T> {
-    pub fn unwrap(self) -> T {
-        Arc::into_inner(self.0).unwrap().into_inner().unwrap().unwrap()
-    }
+///Output of a future driven by the Executor, empty until the future completes
+pub struct Pending<T: 'static>(Rc<RefCell<Option<T>>>);
+impl<T: 'static> Pending<T> {
+    pub fn try_take(&self) -> Option<T> {self.0.borrow_mut().take()}
 }
 
-pub struct BlockingRuntime;
-impl BlockingRuntime {
-    pub fn block_on<T: 'static>(task: impl Future<Output = T>) -> BlockingFuture<T> {
-        #[cfg(not(target_arch="wasm32"))]
-        let future = BlockingFuture(Arc::new(Mutex::new(Some(
-            //Take current thread and block untill future completes
-            tokio::runtime::Builder::new_current_thread().enable_time().enable_io().build().unwrap().block_on(task)
-        ))));
+///Long lived executor that drives the WindowAppTrait futures,
+///timers and IO spawned from an event keep running between events
+pub struct Executor {
+    #[cfg(not(target_arch="wasm32"))]
+    runtime: tokio::runtime::Runtime
+}
 
-        #[cfg(target_arch="wasm32")]
-        let future = BlockingFuture::default();
+impl Executor {
+    pub fn new() -> Self {
+        Executor{
+            #[cfg(not(target_arch="wasm32"))]
+            runtime: tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap()
+        }
+    }
+
+    pub fn spawn<T: 'static>(&self, task: impl Future<Output = T> + 'static) -> Pending<T> {
+        let pending = Pending(Rc::new(RefCell::new(None)));
+
+        //Block the event loop thread untill the future completes, spawned tasks continue on the runtime
+        #[cfg(not(target_arch="wasm32"))]
+        {*pending.0.borrow_mut() = Some(self.runtime.block_on(task));}
 
         #[cfg(target_arch="wasm32")]
-        let arcm = future.0.clone();
-        #[cfg(target_arch="wasm32")]
-        wasm_bindgen_futures::spawn_local(async move {*arcm.lock().unwrap() = Some(task.await);});
+        {
+            let output = pending.0.clone();
+            wasm_bindgen_futures::spawn_local(async move {*output.borrow_mut() = Some(task.await);});
+        }
 
-        future
+        pending
     }
 }
 
 pub struct Winit<A: WindowAppTrait + 'static> {
     scale_factor: f64,
-    future: Option<BlockingFuture<A>>,
+    executor: Executor,
+    future: Option<Pending<A>>,
+    events: Vec<WindowEvent<Arc<Window>>>,
     window: Option<Arc<Window>>,
     mouse: (u32, u32, f32, f32), // x, y, mouse wheel threshold x, y
     size: (u32, u32),
@@ -57,7 +70,9 @@ impl<A: WindowAppTrait + 'static> Winit<A> {
     pub fn new(name: PathBuf) -> Self {
         Winit{
             scale_factor: 0.0,
+            executor: Executor::new(),
             future: None,
+            events: Vec::new(),
             window: None,
             mouse: (0, 0, 0.0, 0.0),
             size: (0, 0),
@@ -96,21 +111,26 @@ impl<A: WindowAppTrait + 'static> Winit<A> {
 
     fn close(&mut self) {
         self.check_future();
-        BlockingRuntime::block_on(self.app.take().unwrap().close());
+        if let Some(app) = self.app.take() {self.executor.spawn(app.close());}
     }
 
     fn window(&self) -> Arc<Window> {self.window.clone().unwrap()}
 
     fn check_future(&mut self) {
-        if let Some(future) = self.future.take() {self.app = Some(future.unwrap());}
+        if let Some(app) = self.future.as_ref().and_then(|future| future.try_take()) {
+            self.future = None;
+            self.app = Some(app);
+        }
     }
 
     fn app_event(&mut self, event: WindowEvent<Arc<Window>>) {
         self.check_future();
-        if self.app.is_none() {return;}//Already Closed
-        let mut app = self.app.take().unwrap();
-        self.future = Some(BlockingRuntime::block_on(async move {
-            app.on_event(event).await;
+        if self.app.is_none() && self.future.is_none() {return;}//Already Closed
+        self.events.push(event);
+        let Some(mut app) = self.app.take() else {return;};//Still running, events are queued
+        let events = std::mem::take(&mut self.events);
+        self.future = Some(self.executor.spawn(async move {
+            for event in events {app.on_event(event).await;}
             app
         }));
     }
@@ -131,12 +151,14 @@ impl<A: WindowAppTrait + 'static> ApplicationHandler for Winit<A> {
         let scale_factor = self.window().scale_factor();
         self.scale_factor = scale_factor;
         let window = self.window.clone().unwrap();
-        if self.app.is_some() {
+        self.check_future();
+        //A running app sits in the future between events, it is only started once
+        if self.app.is_some() || self.future.is_some() {
             self.app_event(WindowEvent::Resumed{
                 window: window.clone(), width: size.width, height: size.height, scale_factor
             });
         } else {
-            self.future = Some(BlockingRuntime::block_on(A::new(
+            self.future = Some(self.executor.spawn(A::new(
                 self.name.take().unwrap(), window, size.width, size.height, scale_factor
             )))
         }
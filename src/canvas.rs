@@ -14,6 +14,8 @@ pub use canvas::{MouseState, KeyboardState, NamedKey, SmolStr, Key, Cursor};
 pub use crate::base::HeadlessContext;
 
 pub trait App {
+    ///Registers Field migrations and encodings, runs before any stored data is read
+    fn register_fields() {}
     fn background_tasks(ctx: &mut HeadlessContext) -> impl Future<Output = Tasks>;
     fn new(ctx: &mut Context<'_>) -> impl Future<Output = (Self, Tasks)> where Self: Sized;
     fn on_event(&mut self, ctx: &mut Context<'_>, event: Event);
@@ -58,6 +60,8 @@ pub struct CanvasApp<A: App> {
 impl<A: App> BaseAppTrait<Canvas> for CanvasApp<A> {
     const LOG_LEVEL: log::Level = log::Level::Error;
 
+    fn register_fields() {A::register_fields()}
+
     async fn background_tasks(ctx: &mut HeadlessContext) -> Tasks {A::background_tasks(ctx).await}
 
     async fn new<'a>(base_context: &'a mut base::Context<'a, Canvas>, _ctx: &mut HeadlessContext, width: f32, height: f32) -> (Self, Tasks) {
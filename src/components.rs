@@ -5,8 +5,9 @@ pub use include_dir;
 pub use proc::{Component, Plugin};
 
 use base::{BaseAppTrait, HeadlessContext};
-use base::driver::runtime::{Tasks};
-use base::driver::state::State;
+use base::driver::runtime::{Tasks, Task, TaskHandle, TaskMetrics, SHUTDOWN_TIMEOUT};
+use base::driver::state::{State, StateSubscription};
+use base::driver::store::Store;
 use base::renderer::wgpu_canvas as canvas;
 pub use canvas::Canvas;
 use canvas::Context as CanvasContext;
@@ -16,13 +17,13 @@ use include_dir::{DirEntry, Dir};
 
 use std::collections::HashMap;
 use std::future::Future;
-use std::time::Instant;
+use std::time::{Instant, Duration};
 use std::any::TypeId;
 
 mod events;
 pub use events::{
     Events, OnEvent, Event, TickEvent, MouseEvent, MouseState,
-    KeyboardEvent, KeyboardState, NamedKey, Key, SmolStr
+    KeyboardEvent, KeyboardState, NamedKey, Key, SmolStr, UndoEvent, RedoEvent
 };
 
 pub mod resources;
@@ -41,12 +42,16 @@ pub struct Context {
     assets: Assets,
     events: Events,
     base_context: base::Context<Canvas>,
+    redraw: bool
 }
 
 impl Context {
     pub fn new(base_context: base::Context<Canvas>) -> Self {
-        Context{plugins: Plugins::new(), assets: Assets::new(), events: Events::new(), base_context}
+        Context{plugins: Plugins::new(), assets: Assets::new(), events: Events::new(), base_context, redraw: true}
     }
+
+    ///Rebuilds and redraws the app this frame, for changes not caused by an event or State
+    pub fn request_redraw(&mut self) {self.redraw = true;}
         
     pub fn trigger_event(&mut self, event: impl Event) {
         self.events.push_back(Box::new(event));
@@ -59,6 +64,13 @@ impl Context {
     }
 
     pub fn state(&mut self) -> &mut State {self.base_context.state()}
+    pub fn store(&self) -> &Store {self.base_context.store()}
+    pub async fn restore_state(&mut self) {self.base_context.restore_state().await}
+
+    pub fn spawn_task(&mut self, task: impl Task + 'static) -> TaskHandle {self.base_context.spawn_task(task)}
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + Send + 'static) -> TaskHandle {self.base_context.spawn(future)}
+    pub fn task_metrics(&mut self) -> Vec<TaskMetrics> {self.base_context.task_metrics()}
+    pub fn log_task_metrics(&mut self, level: log::Level) {self.base_context.log_task_metrics(level)}
 
     pub fn include_assets(&mut self, dir: Dir<'static>) {
         self.assets.push(dir);
@@ -100,6 +112,13 @@ impl AsMut<wgpu_canvas::FontAtlas> for Context {
     fn as_mut(&mut self) -> &mut wgpu_canvas::FontAtlas {self.base_context.as_mut().as_mut()}
 }
 
+impl HeadlessContext {
+    ///Queues an event for the components, it is passed down the tree on the next Tick
+    pub fn trigger_event(&self, event: impl Event) {
+        self.send(Box::new(Box::new(event) as Box<dyn Event>));
+    }
+}
+
 pub trait Plugin {
     fn background_tasks(_ctx: &mut HeadlessContext) -> impl Future<Output = Tasks> {async {vec![]}}
     fn new(
@@ -110,6 +129,12 @@ pub trait Plugin {
 pub type Plugins = HashMap<TypeId, Box<dyn std::any::Any>>;
 
 pub trait App {
+    ///Time tasks are given to finish when the app closes
+    const SHUTDOWN_TIMEOUT: Duration = SHUTDOWN_TIMEOUT;
+
+    ///Registers Field migrations and encodings, runs before any stored data is read
+    fn register_fields() {}
+
     fn background_tasks(_ctx: &mut HeadlessContext) -> impl Future<Output = Tasks> {async {vec![]}}
 
     fn plugins(
@@ -124,6 +149,13 @@ pub struct ComponentApp<A: App> {
     app: Box<dyn Drawable>,
     screen: (f32, f32),
     sized_app: SizedBranch,
+    //State read while building the last frame
+    dependencies: Vec<StateSubscription>,
+    //Versions of the Store values read while building the last frame
+    stored: HashMap<TypeId, u64>,
+    //Held modifiers for the undo and redo shortcuts, Super stands in for Control on macOS
+    ctrl: bool,
+    shift: bool,
     _p: std::marker::PhantomData<A>,
 
     time: Instant
@@ -131,6 +163,9 @@ pub struct ComponentApp<A: App> {
 
 impl<A: App> BaseAppTrait<Canvas> for ComponentApp<A> {
     const LOG_LEVEL: log::Level = log::Level::Error;
+    const SHUTDOWN_TIMEOUT: Duration = A::SHUTDOWN_TIMEOUT;
+
+    fn register_fields() {A::register_fields()}
 
     async fn background_tasks(ctx: &mut HeadlessContext) -> Tasks {
         A::background_tasks(ctx).await
@@ -147,7 +182,7 @@ impl<A: App> BaseAppTrait<Canvas> for ComponentApp<A> {
         let screen = (width, height);
         let sized_app = app.build(&mut ctx, screen, size_request);
         (
-            ComponentApp{ctx, app, screen, sized_app, _p: std::marker::PhantomData::<A>, time: Instant::now()},
+            ComponentApp{ctx, app, screen, sized_app, dependencies: vec![], stored: HashMap::new(), ctrl: false, shift: false, _p: std::marker::PhantomData::<A>, time: Instant::now()},
             tasks
         )
     }
@@ -157,30 +192,63 @@ impl<A: App> BaseAppTrait<Canvas> for ComponentApp<A> {
     fn on_event(&mut self, event: canvas::Event) {
         match event {
             canvas::Event::Resized{width, height} | canvas::Event::Resumed{width, height} => {
+                if let canvas::Event::Resumed{..} = event {(self.ctrl, self.shift) = (false, false);}
                 self.screen = (width, height);
+                self.ctx.redraw = true;
             },
             canvas::Event::Mouse{position, state} => {
                 self.ctx.events.push_back(Box::new(MouseEvent{position: Some(position), state}));
             },
             canvas::Event::Keyboard{key, state} => {
+                let pressed = state == KeyboardState::Pressed;
+                let shortcut = match &key {
+                    //Shortcuts use Command on Apple platforms and Control everywhere else
+                    Key::Named(NamedKey::Super) if cfg!(any(target_os = "macos", target_os = "ios")) => {self.ctrl = pressed; None},
+                    Key::Named(NamedKey::Control) if !cfg!(any(target_os = "macos", target_os = "ios")) => {self.ctrl = pressed; None},
+                    Key::Named(NamedKey::Shift) => {self.shift = pressed; None},
+                    Key::Character(c) if pressed && self.ctrl => match c.to_lowercase().as_str() {
+                        "z" if self.shift => Some(Box::new(RedoEvent) as Box<dyn Event>),
+                        "z" => Some(Box::new(UndoEvent) as Box<dyn Event>),
+                        "y" => Some(Box::new(RedoEvent) as Box<dyn Event>),
+                        _ => None
+                    },
+                    _ => None
+                };
                 self.ctx.events.push_back(Box::new(KeyboardEvent{key, state}));
+                if let Some(shortcut) = shortcut {self.ctx.events.push_back(shortcut);}
             },
             canvas::Event::Tick => {
                 log::error!("last_frame: {:?}", self.time.elapsed());
                 self.time = Instant::now();
 
+                for message in self.ctx.base_context.messages() {
+                    if let Ok(event) = message.downcast::<Box<dyn Event>>() {self.ctx.events.push_back(*event);}
+                }
+
                 self.app.event(&mut self.ctx, self.sized_app.clone(), Box::new(TickEvent));
+                if !self.ctx.events.is_empty() {self.ctx.redraw = true;}
                 while let Some(event) = self.ctx.events.pop_front() {
                     if let Some(event) = event.pass(&mut self.ctx, vec![((0.0, 0.0), self.sized_app.0)]).remove(0) {
                         self.app.event(&mut self.ctx, self.sized_app.clone(), event)
                     }
                 }
 
-                let size_request = _Drawable::request_size(&*self.app, &mut self.ctx);
-                self.sized_app = self.app.build(&mut self.ctx, self.screen, size_request);
-                self.app.draw(&mut self.ctx, self.sized_app.clone(), (0.0, 0.0), (0.0, 0.0, self.screen.0, self.screen.1));
+                //Without events, State or Store changes or requests the canvas keeps showing the last frame
+                let stored = self.ctx.store().changed(&self.stored);
+                let state = self.ctx.base_context.state();
+                let changed = stored || self.dependencies.iter_mut().any(|d| d.changed(state));
+                if std::mem::take(&mut self.ctx.redraw) || changed {
+                    self.ctx.state().track();
+                    Store::track();
+                    let size_request = _Drawable::request_size(&*self.app, &mut self.ctx);
+                    self.sized_app = self.app.build(&mut self.ctx, self.screen, size_request);
+                    self.app.draw(&mut self.ctx, self.sized_app.clone(), (0.0, 0.0), (0.0, 0.0, self.screen.0, self.screen.1));
+                    self.dependencies = self.ctx.state().dependencies();
+                    self.stored = Store::dependencies();
+                }
             },
-            _ => {}
+            //Keys released while the app is in the background never reach it
+            canvas::Event::Paused => (self.ctrl, self.shift) = (false, false)
         }
     }
 
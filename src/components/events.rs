@@ -53,6 +53,7 @@ impl Event for KeyboardEvent {
     }
 }
 
+///Sent every frame, components animating on it call `Context::request_redraw`
 #[derive(Debug, Clone, Copy)]
 pub struct TickEvent;
 impl Event for TickEvent {
@@ -60,3 +61,21 @@ impl Event for TickEvent {
         children.into_iter().map(|_| Some(Box::new(*self) as Box<dyn Event>)).collect()
     }
 }
+
+///Sent after the KeyboardEvent for Ctrl+Z, components bind it to `State::undo`
+#[derive(Debug, Clone, Copy)]
+pub struct UndoEvent;
+impl Event for UndoEvent {
+    fn pass(self: Box<Self>, _ctx: &mut Context, children: Vec<((f32, f32), (f32, f32))>) -> Vec<Option<Box<dyn Event>>> {
+        children.into_iter().map(|_| Some(Box::new(*self) as Box<dyn Event>)).collect()
+    }
+}
+
+///Sent after the KeyboardEvent for Ctrl+Y or Ctrl+Shift+Z, components bind it to `State::redo`
+#[derive(Debug, Clone, Copy)]
+pub struct RedoEvent;
+impl Event for RedoEvent {
+    fn pass(self: Box<Self>, _ctx: &mut Context, children: Vec<((f32, f32), (f32, f32))>) -> Vec<Option<Box<dyn Event>>> {
+        children.into_iter().map(|_| Some(Box::new(*self) as Box<dyn Event>)).collect()
+    }
+}
@@ -2,9 +2,12 @@ mod base;
 pub use base::{BackgroundApp, HeadlessContext, BaseApp};
 pub use base::window::WindowApp;
 pub use base::renderer::RenderApp;
-pub use base::driver::runtime::{Task, Tasks, async_trait};
-pub use base::driver::state::{State, Field};
-pub use base::driver::cache::Cache;
+pub use base::driver::runtime::{Task, Tasks, TaskHandle, TaskResult, TaskError, Retry, Limit, Schedule, Cron, InvalidCron, PausePolicy, TaskMetrics, async_trait};
+pub use base::driver::state::{State, StateSubscription, Field, FieldError, Migration, Encoding};
+pub use base::driver::cache::{Cache, CacheError, CacheMaintenance, CacheTransaction, CacheBackend, MemoryBackend, CacheSubscription, CacheKey};
+#[cfg(not(target_arch = "wasm32"))]
+pub use base::driver::cache::SqliteBackend;
+pub use base::driver::store::{Store, StoreSubscription};
 pub use base::driver::camera::{Camera, CameraViewError};
 #[cfg(target_os="ios")]
 pub use base::get_application_support_dir;